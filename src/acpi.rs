@@ -0,0 +1,189 @@
+// src/acpi.rs
+
+use x86_64::{PhysAddr, VirtAddr};
+
+// maximum number of ISA interrupt source overrides that are kept
+const ISA_IRQS: usize = 16;
+
+// an I/O APIC described by the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    // physical address of its registers
+    pub addr: PhysAddr,
+    // first global system interrupt it handles
+    pub gsi_base: u32,
+}
+
+// how an ISA interrupt line is connected to the I/O APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaIrq {
+    // global system interrupt the line is connected to
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+// the parts of the Multiple APIC Description Table the kernel uses
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    // physical address of the local APIC registers
+    pub local_apic_addr: PhysAddr,
+    // whether the system also has the legacy 8259 PICs
+    pub has_8259: bool,
+    // the first I/O APIC. Systems with more than one are not supported
+    pub io_apic: Option<IoApicInfo>,
+    overrides: [Option<IsaIrq>; ISA_IRQS],
+}
+
+impl Madt {
+    // returns how the ISA interrupt 'irq' is connected
+    //
+    // without an override, ISA line n is global system interrupt n,
+    // active high and edge triggered
+    pub fn isa_irq(&self, irq: u8) -> IsaIrq {
+        self.overrides
+            .get(usize::from(irq))
+            .copied()
+            .flatten()
+            .unwrap_or(IsaIrq { gsi: u32::from(irq), active_low: false, level_triggered: false })
+    }
+
+    // returns whether the line 'irq' is not connected: it has no override, but
+    // another line is redirected to the global system interrupt it would use
+    pub fn isa_irq_replaced(&self, irq: u8) -> bool {
+        if self.overrides.get(usize::from(irq)).copied().flatten().is_some() {
+            return false;
+        }
+        self.overrides
+            .iter()
+            .flatten()
+            .any(|other| other.gsi == u32::from(irq))
+    }
+}
+
+// finds the ACPI tables through the RSDP and parses the MADT
+//
+// reads physical memory through the mapping at 'physical_memory_offset'.
+// Returns None if there is no valid RSDP or no MADT
+pub fn find_madt(physical_memory_offset: VirtAddr) -> Option<Madt> {
+    let memory = PhysicalMemory { offset: physical_memory_offset };
+    let rsdp = find_rsdp(&memory)?;
+    let madt = find_table(&memory, rsdp, *b"APIC")?;
+    Some(parse_madt(&memory, madt))
+}
+
+// reads physical memory through the complete mapping set up by the bootloader
+struct PhysicalMemory {
+    offset: VirtAddr,
+}
+
+impl PhysicalMemory {
+    fn read<T: Copy>(&self, addr: u64) -> T {
+        unsafe { (self.offset + addr).as_ptr::<T>().read_unaligned() }
+    }
+
+    fn checksum_ok(&self, addr: u64, len: u64) -> bool {
+        (0..len).fold(0u8, |sum, i| sum.wrapping_add(self.read(addr + i))) == 0
+    }
+}
+
+// returns the physical address of the Root System Description Pointer
+//
+// it lies on a 16 byte boundary in the first KiB of the Extended BIOS Data
+// Area or in the BIOS area below 1 MiB
+fn find_rsdp(memory: &PhysicalMemory) -> Option<u64> {
+    // the BIOS data area holds the segment of the EBDA
+    let ebda = u64::from(memory.read::<u16>(0x40e)) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+    areas
+        .into_iter()
+        .filter(|&(start, _)| start != 0)
+        .flat_map(|(start, end)| (start..end).step_by(16))
+        .find(|&addr| memory.read::<[u8; 8]>(addr) == *b"RSD PTR " && memory.checksum_ok(addr, 20))
+}
+
+// returns the physical address of the table with the given signature
+fn find_table(memory: &PhysicalMemory, rsdp: u64, signature: [u8; 4]) -> Option<u64> {
+    // ACPI 2.0 and later have the XSDT with 64 bit pointers
+    let revision: u8 = memory.read(rsdp + 15);
+    let xsdt: u64 = memory.read(rsdp + 24);
+    let (root, entry_size) = if revision >= 2 && xsdt != 0 {
+        (xsdt, 8)
+    } else {
+        (u64::from(memory.read::<u32>(rsdp + 16)), 4)
+    };
+
+    let length = u64::from(memory.read::<u32>(root + 4));
+    if !memory.checksum_ok(root, length) {
+        return None;
+    }
+    (root + HEADER_SIZE..root + length)
+        .step_by(entry_size)
+        .map(|entry| match entry_size {
+            8 => memory.read::<u64>(entry),
+            _ => u64::from(memory.read::<u32>(entry)),
+        })
+        .find(|&table| {
+            memory.read::<[u8; 4]>(table) == signature
+                && memory.checksum_ok(table, u64::from(memory.read::<u32>(table + 4)))
+        })
+}
+
+// size of the header all system description tables start with
+const HEADER_SIZE: u64 = 36;
+
+// types of the MADT entries
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+
+fn parse_madt(memory: &PhysicalMemory, madt: u64) -> Madt {
+    let length = u64::from(memory.read::<u32>(madt + 4));
+    let flags: u32 = memory.read(madt + HEADER_SIZE + 4);
+    let mut result = Madt {
+        local_apic_addr: PhysAddr::new(u64::from(memory.read::<u32>(madt + HEADER_SIZE))),
+        has_8259: flags & 1 != 0,
+        io_apic: None,
+        overrides: [None; ISA_IRQS],
+    };
+
+    // the entries follow the local APIC address and the flags
+    let mut entry = madt + HEADER_SIZE + 8;
+    while entry + 2 <= madt + length {
+        let entry_type: u8 = memory.read(entry);
+        let entry_length: u8 = memory.read(entry + 1);
+        if entry_length < 2 {
+            break;
+        }
+        match entry_type {
+            IO_APIC if result.io_apic.is_none() => {
+                result.io_apic = Some(IoApicInfo {
+                    id: memory.read(entry + 2),
+                    addr: PhysAddr::new(u64::from(memory.read::<u32>(entry + 4))),
+                    gsi_base: memory.read(entry + 8),
+                });
+            }
+            INTERRUPT_SOURCE_OVERRIDE => {
+                let bus: u8 = memory.read(entry + 2);
+                let source: u8 = memory.read(entry + 3);
+                let gsi: u32 = memory.read(entry + 4);
+                let flags: u16 = memory.read(entry + 8);
+                // bus 0 is ISA, polarity and trigger mode 0 mean the ISA default
+                if bus == 0 && usize::from(source) < ISA_IRQS {
+                    result.overrides[usize::from(source)] = Some(IsaIrq {
+                        gsi,
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    });
+                }
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => {
+                result.local_apic_addr = PhysAddr::new(memory.read(entry + 4));
+            }
+            _ => {}
+        }
+        entry += u64::from(entry_length);
+    }
+    result
+}
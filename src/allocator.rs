@@ -3,24 +3,23 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
 
-use linked_list_allocator::LockedHeap;
-use linked_list::LinkedListAllocator;
-use fixed_size_block::FixedSizeBlockAllocator;
-
-use bump::BumpAllocator;
-
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, PageSize, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
+use crate::memory::{self, region::{Region, RegionKind}};
+use crate::serial_println;
+
 pub struct Dummy;
 
 pub mod bump;
 pub mod linked_list;
 pub mod fixed_size_block;
+pub mod debug;
+pub mod oom;
 
 // a wrapper around spin::Mutex to permit trait implementations
 pub struct Locked<A> {
@@ -39,6 +38,96 @@ impl<A> Locked<A> {
     }
 }
 
+// usage statistics reported by the heap allocators
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: usize,   // bytes of memory managed by the allocator
+    pub used: usize,        // bytes currently handed out
+    pub peak_used: usize,   // highest value 'used' has reached
+    pub allocations: usize, // number of live allocations
+    pub in_place_reallocs: usize, // reallocs served without moving the data
+    pub moved_reallocs: usize,    // reallocs that copied the data to a new block
+}
+
+impl HeapStats {
+    // statistics of an allocator that has not been initialized yet
+    const fn empty() -> Self {
+        HeapStats {
+            heap_size: 0,
+            used: 0,
+            peak_used: 0,
+            allocations: 0,
+            in_place_reallocs: 0,
+            moved_reallocs: 0,
+        }
+    }
+
+    // bytes that are not handed out
+    pub fn free(&self) -> usize {
+        self.heap_size - self.used
+    }
+
+    // account for a new allocation of 'size' bytes
+    fn record_alloc(&mut self, size: usize) {
+        self.used += size;
+        self.allocations += 1;
+        self.peak_used = self.peak_used.max(self.used);
+    }
+
+    // account for a freed allocation of 'size' bytes
+    fn record_dealloc(&mut self, size: usize) {
+        self.used -= size;
+        self.allocations -= 1;
+    }
+
+    // account for an allocation resized from 'old_size' to 'new_size' bytes in place
+    fn record_realloc_in_place(&mut self, old_size: usize, new_size: usize) {
+        self.used = self.used - old_size + new_size;
+        self.peak_used = self.peak_used.max(self.used);
+        self.in_place_reallocs += 1;
+    }
+}
+
+// allocators that can report how much of their heap is in use
+pub trait HeapStatistics {
+    fn stats(&self) -> HeapStats;
+}
+
+// linked_list_allocator only knows its size and used bytes
+impl HeapStatistics for linked_list_allocator::LockedHeap {
+    fn stats(&self) -> HeapStats {
+        let heap = self.lock();
+        HeapStats {
+            heap_size: heap.size(),
+            used: heap.used(),
+            peak_used: heap.used(), // not tracked
+            allocations: 0,         // not tracked
+            in_place_reallocs: 0,   // not tracked
+            moved_reallocs: 0,      // not tracked
+        }
+    }
+}
+
+// moves an allocation into a new block, like the default GlobalAlloc::realloc
+//
+// used by the allocators once resizing in place failed
+unsafe fn realloc_by_copy(
+    allocator: &impl GlobalAlloc,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+) -> *mut u8 {
+    let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+    let new_ptr = unsafe { allocator.alloc(new_layout) };
+    if !new_ptr.is_null() {
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            allocator.dealloc(ptr, layout);
+        }
+    }
+    new_ptr
+}
+
 // align the given address 'addr' upward to alignment 'align' using bitwise operations
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
@@ -58,49 +147,152 @@ unsafe impl GlobalAlloc for Dummy {
 #[global_allocator]
 static ALLOCATOR: Dummy = Dummy;
 */
-//#[global_allocator]
-//static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
-//#[global_allocator]
-//static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+// --- global allocator selection ---
+// exactly one of the alloc-* cargo features picks the global allocator,
+// alloc-fixed-block is the default
+// with the alloc-debug feature the chosen allocator is wrapped in a DebugAllocator
+// either way, the global allocator is a ReclaimingAllocator on top
 
-//#[global_allocator]
-//static ALLOCATOR: Locked<LinkedListAllocator> =
-//    Locked::new(LinkedListAllocator::new());
+#[cfg(any(
+    all(feature = "alloc-bump", any(feature = "alloc-linked-list", feature = "alloc-fixed-block", feature = "alloc-external")),
+    all(feature = "alloc-linked-list", any(feature = "alloc-fixed-block", feature = "alloc-external")),
+    all(feature = "alloc-fixed-block", feature = "alloc-external"),
+))]
+compile_error!("only one of the alloc-* features can be enabled");
 
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+    feature = "alloc-external",
+)))]
+compile_error!("one of the alloc-* features must be enabled");
+
+#[cfg(feature = "alloc-external")]
+static ALLOCATOR: linked_list_allocator::LockedHeap = linked_list_allocator::LockedHeap::empty();
+
+#[cfg(feature = "alloc-bump")]
+static ALLOCATOR: Locked<bump::BumpAllocator> = Locked::new(bump::BumpAllocator::new());
+
+#[cfg(feature = "alloc-linked-list")]
+static ALLOCATOR: Locked<linked_list::LinkedListAllocator> =
+    Locked::new(linked_list::LinkedListAllocator::new());
+
+#[cfg(feature = "alloc-fixed-block")]
+static ALLOCATOR: Locked<fixed_size_block::FixedSizeBlockAllocator> =
+    Locked::new(fixed_size_block::FixedSizeBlockAllocator::new());
+
+#[cfg(feature = "alloc-debug")]
+static DEBUG_ALLOCATOR: debug::DebugAllocator = debug::DebugAllocator::new(&ALLOCATOR);
+
+// the global allocator runs the reclaim hooks before an allocation fails
+#[cfg(not(feature = "alloc-debug"))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: oom::ReclaimingAllocator = oom::ReclaimingAllocator::new(&ALLOCATOR);
+
+#[cfg(feature = "alloc-debug")]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> =
-    Locked::new(FixedSizeBlockAllocator::new());
+static GLOBAL_ALLOCATOR: oom::ReclaimingAllocator = oom::ReclaimingAllocator::new(&DEBUG_ALLOCATOR);
+
+// returns the usage statistics of the global allocator
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+// returns the usage of each block size class of the global allocator
+#[cfg(feature = "alloc-fixed-block")]
+pub fn block_stats() -> [fixed_size_block::BlockClassStats; fixed_size_block::BLOCK_SIZES.len()] {
+    ALLOCATOR.lock().block_stats()
+}
+
+// prints a heap usage report over serial
+pub fn print_heap_report() {
+    let stats = heap_stats();
+    serial_println!("--- heap usage ---");
+    serial_println!("size:        {} bytes", stats.heap_size);
+    serial_println!("used:        {} bytes", stats.used);
+    serial_println!("free:        {} bytes", stats.free());
+    serial_println!("peak used:   {} bytes", stats.peak_used);
+    serial_println!("allocations: {}", stats.allocations);
+    serial_println!("reallocs:    {} in place, {} moved", stats.in_place_reallocs, stats.moved_reallocs);
+
+    #[cfg(feature = "alloc-fixed-block")]
+    {
+        serial_println!("block size  allocated  free");
+        for class in block_stats() {
+            serial_println!("{:>10}  {:>9}  {:>4}", class.block_size, class.allocated, class.free);
+        }
+    }
+}
 
 // Creates a kernel heap from which we can allocate memory later
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+// the heap grows on demand up to this size
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+// minimum number of bytes mapped each time the heap grows
+const HEAP_GROW_SIZE: usize = 64 * 1024; // 64 KiB
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {   
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-        };
-    }
+// maps the initial heap through the kernel memory
+// memory::init_kernel_memory must be called before
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    memory::with_kernel_memory(|memory| {
+        // keep other regions out of the range the heap can grow into
+        memory.regions
+            .insert(Region {
+                start: VirtAddr::new(HEAP_START as u64),
+                size: HEAP_MAX_SIZE as u64,
+                kind: RegionKind::Heap,
+                flags: HEAP_FLAGS,
+                fault_handler: None,
+            })
+            .expect("heap region already in use");
+        memory.map_range(VirtAddr::new(HEAP_START as u64), None, HEAP_SIZE as u64, HEAP_FLAGS)
+    })
+    .expect("kernel memory not initialized")?;
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// maps at least 'min_size' more bytes directly after 'heap_end'
+// without growing the heap beyond HEAP_MAX_SIZE
+//
+// returns the number of bytes that were mapped, which may be less than
+// 'min_size' if the frame allocator runs out of frames, or 0 if the kernel
+// memory is locked
+pub(crate) fn grow_heap(heap_end: usize, min_size: usize) -> usize {
+    if heap_end < HEAP_START {
+        return 0; // heap not initialized
+    }
+    let size = align_up(min_size.max(HEAP_GROW_SIZE), Size4KiB::SIZE as usize)
+        .min(HEAP_START + HEAP_MAX_SIZE - heap_end);
+    if size < min_size {
+        return 0; // limit reached
+    }
+
+    // parts of the heap that cover a whole huge page are mapped with one
+    // the allocator lock is held here, so waiting for the kernel memory could
+    // deadlock with an allocation made while the kernel memory is locked
+    memory::try_with_kernel_memory(|memory| {
+        let mut mapped = 0;
+        while mapped < size {
+            let addr = VirtAddr::new((heap_end + mapped) as u64);
+            match memory.map_largest_page(addr, None, (size - mapped) as u64, HEAP_FLAGS) {
+                Ok(page_size) => mapped += page_size as usize,
+                Err(_) => break,
+            }
+        }
+        mapped
+    })
+    .unwrap_or(0)
+}
+
+// flags of the heap pages
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
@@ -1,7 +1,7 @@
 // src/allocator/bump.rs
 
 use alloc::alloc::{GlobalAlloc, Layout};
-use super::{align_up, Locked};
+use super::{align_up, HeapStatistics, HeapStats, Locked};
 use core::ptr;
 
 pub struct BumpAllocator {
@@ -9,6 +9,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    peak_used: usize,
 }
 
 impl BumpAllocator {
@@ -19,6 +20,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            peak_used: 0,
         }
     }
 
@@ -28,6 +30,19 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    // returns the usage statistics
+    // memory counts as used until all allocations are freed
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            heap_size: self.heap_end - self.heap_start,
+            used: self.next - self.heap_start,
+            peak_used: self.peak_used,
+            allocations: self.allocations,
+            in_place_reallocs: 0,
+            moved_reallocs: 0,
+        }
+    }
 }
 
 
@@ -47,6 +62,7 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.peak_used = bump.peak_used.max(alloc_end - bump.heap_start);
             alloc_start as *mut u8
         }
     }
@@ -59,4 +75,10 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             bump.next = bump.heap_start; // reset if no allocations are left
         }
     }
-}
\ No newline at end of file
+}
+
+impl HeapStatistics for Locked<BumpAllocator> {
+    fn stats(&self) -> HeapStats {
+        self.lock().stats()
+    }
+}
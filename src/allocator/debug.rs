@@ -0,0 +1,123 @@
+// src/allocator/debug.rs
+
+use super::align_up;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+// byte pattern written over freed allocations
+pub const POISON: u8 = 0xde;
+
+// byte pattern of the guard bytes around each allocation
+pub const GUARD: u8 = 0xfd;
+
+// marks a live allocation in its header
+const ALLOCATED_MAGIC: u64 = 0xa110_ca7e_d0c5_a11c;
+
+// marks a freed allocation in its header
+const FREED_MAGIC: u64 = 0xf4ee_d0c5_f4ee_d0c5;
+
+// bytes at the start of each block that are left to the wrapped allocator,
+// which stores its free list nodes there
+const RESERVED_SIZE: usize = 16;
+
+// size of the header: reserved bytes, magic and requested size
+const HEADER_SIZE: usize = RESERVED_SIZE + 16;
+
+// minimum number of guard bytes in front of and behind each allocation
+const GUARD_SIZE: usize = 16;
+
+// header stored in front of each allocation, after the reserved bytes
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+}
+
+// a wrapper that checks every allocation of another allocator
+//
+// each allocation gets a header and guard bytes in front of and behind it.
+// On 'dealloc', the header detects double frees and the guard bytes detect
+// overflows, both of which panic with the address and layout. Freed memory
+// is filled with 'POISON' so that use-after-free bugs show up as garbage
+//
+// a double free is only detected as long as the block was not handed out
+// again by the wrapped allocator
+pub struct DebugAllocator {
+    inner: &'static (dyn GlobalAlloc + Sync),
+}
+
+impl DebugAllocator {
+    // creates a debug allocator that forwards to 'inner'
+    pub const fn new(inner: &'static (dyn GlobalAlloc + Sync)) -> Self {
+        DebugAllocator { inner }
+    }
+}
+
+// returns the offset of the user data from the start of the block
+fn data_offset(layout: &Layout) -> usize {
+    align_up(HEADER_SIZE + GUARD_SIZE, layout.align())
+}
+
+// returns the layout of the whole block requested from the wrapped allocator
+fn block_layout(layout: &Layout) -> Layout {
+    let size = data_offset(layout) + layout.size() + GUARD_SIZE;
+    let align = layout.align().max(RESERVED_SIZE);
+    Layout::from_size_align(size, align).expect("debug allocator layout overflow")
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let block = unsafe { self.inner.alloc(block_layout(&layout)) };
+        if block.is_null() {
+            return block;
+        }
+        let offset = data_offset(&layout);
+        unsafe {
+            let header = block.add(RESERVED_SIZE) as *mut Header;
+            header.write(Header {
+                magic: ALLOCATED_MAGIC,
+                size: layout.size(),
+            });
+            ptr::write_bytes(block.add(HEADER_SIZE), GUARD, offset - HEADER_SIZE);
+            ptr::write_bytes(block.add(offset + layout.size()), GUARD, GUARD_SIZE);
+            block.add(offset)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let offset = data_offset(&layout);
+        let block = unsafe { ptr.sub(offset) };
+        let header = unsafe { &mut *(block.add(RESERVED_SIZE) as *mut Header) };
+
+        match header.magic {
+            ALLOCATED_MAGIC => {}
+            FREED_MAGIC => panic!("double free of {:p} with {:?}", ptr, layout),
+            _ => panic!("free of {:p} with {:?} that was never allocated", ptr, layout),
+        }
+        if header.size != layout.size() {
+            panic!(
+                "free of {:p} with {:?}, but it was allocated with size {}",
+                ptr, layout, header.size
+            );
+        }
+
+        let front_guard = unsafe {
+            core::slice::from_raw_parts(block.add(HEADER_SIZE), offset - HEADER_SIZE)
+        };
+        if front_guard.iter().any(|&b| b != GUARD) {
+            panic!("buffer underflow in front of {:p} with {:?}", ptr, layout);
+        }
+        let back_guard = unsafe {
+            core::slice::from_raw_parts(ptr.add(layout.size()), GUARD_SIZE)
+        };
+        if back_guard.iter().any(|&b| b != GUARD) {
+            panic!("buffer overflow behind {:p} with {:?}", ptr, layout);
+        }
+
+        header.magic = FREED_MAGIC;
+        unsafe {
+            ptr::write_bytes(ptr, POISON, layout.size());
+            self.inner.dealloc(block, block_layout(&layout));
+        }
+    }
+}
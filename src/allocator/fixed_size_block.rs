@@ -4,42 +4,114 @@
 /// -----------
 use alloc::alloc::Layout;
 use core::ptr;
-use super::Locked;
+use super::{align_up, HeapStatistics, HeapStats, Locked};
+use super::linked_list::LinkedListAllocator;
 use alloc::alloc::GlobalAlloc;
 use core::{mem, ptr::NonNull};
 
 /// Node for a linked list of free blocks
 /// 
 /// Each node represents a free block and points to the next free block. Size of the block
-/// is determined by the block size associated with the list head.
+/// is determined by the size class of the slab the list belongs to.
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
+/// Header at the start of every slab.
+///
+/// A slab is a chunk of `slab_size` bytes, aligned to its size, that is taken
+/// from the fallback allocator and cut into blocks of a single block size. The
+/// blocks behind the header that are not handed out form the slab's free list.
+/// Slabs with free blocks are kept in a doubly linked list per block size.
+struct Slab {
+    free_list: Option<&'static mut ListNode>,
+    /// Number of blocks of this slab that are handed out.
+    in_use: usize,
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+}
+
+/// The minimum size of a slab.
+const MIN_SLAB_SIZE: usize = 4096;
+
 /// The block sizes to use.
 ///
 /// The sizes must each be power of 2 bc they are alays used for alignment.
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 /// A fixed size block allocator with a fallback linked list allocator.
 /// 
-/// Stores a linked list of free blocks for each block size in BLOCK_SIZES
-/// and uses a linked list allocator as a fallback for larger allocations.
+/// Serves each block size in BLOCK_SIZES from slabs and uses a linked list
+/// allocator as a fallback for larger allocations. The slabs themselves are
+/// allocated from the fallback allocator and are given back to it once all of
+/// their blocks are free, so a burst of small allocations does not keep the
+/// memory forever. One empty slab per block size is kept to avoid allocating
+/// and freeing a slab over and over.
+/// The fallback is our own `LinkedListAllocator`, because it can resize
+/// allocations in place.
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap,
+    /// Slabs that have free blocks, per block size.
+    partial_slabs: [Option<NonNull<Slab>>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+    stats: HeapStats,
+    /// Number of live allocations per block size.
+    allocated_blocks: [usize; BLOCK_SIZES.len()],
+    /// Number of free blocks in the slabs per block size.
+    free_blocks: [usize; BLOCK_SIZES.len()],
+    /// Number of slabs per block size.
+    slabs: [usize; BLOCK_SIZES.len()],
+}
+
+// The slab pointers are only ever accessed through the allocator, which
+// is protected by the lock in Locked<T>.
+unsafe impl Send for FixedSizeBlockAllocator {}
+
+/// Usage of a single block size class.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockClassStats {
+    pub block_size: usize,
+    /// Number of blocks currently handed out.
+    pub allocated: usize,
+    /// Number of free blocks waiting in the slabs for reuse.
+    pub free: usize,
+    /// Number of slabs the blocks are taken from.
+    pub slabs: usize,
 }
 
 impl FixedSizeBlockAllocator {
     /// Creates an empty fixed size block allocator.
     pub const fn new() -> Self {
-        const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty(),
+            partial_slabs: [None; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+            stats: HeapStats::empty(),
+            allocated_blocks: [0; BLOCK_SIZES.len()],
+            free_blocks: [0; BLOCK_SIZES.len()],
+            slabs: [0; BLOCK_SIZES.len()],
         }
     }
 
+    /// Returns the usage statistics of the whole heap.
+    ///
+    /// Blocks are counted with their full block size. Free blocks waiting in a
+    /// slab count as free memory.
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            heap_size: self.fallback_allocator.size(),
+            ..self.stats
+        }
+    }
+
+    /// Returns the usage of each block size class.
+    pub fn block_stats(&self) -> [BlockClassStats; BLOCK_SIZES.len()] {
+        core::array::from_fn(|index| BlockClassStats {
+            block_size: BLOCK_SIZES[index],
+            allocated: self.allocated_blocks[index],
+            free: self.free_blocks[index],
+            slabs: self.slabs[index],
+        })
+    }
+
     /// Initilize the allocator with the given heap bounds.
     /// 
     /// # Safety
@@ -54,13 +126,184 @@ impl FixedSizeBlockAllocator {
 
     /// Allocates using the fallback allocator.
     /// 
+    /// If the fallback allocator is out of memory, the heap is grown by mapping
+    /// more pages after its current end and the allocation is retried.
     /// Return a pointer to the allocated memory or null if allocation failed.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        let ptr = self.fallback_allocator.allocate(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // the free space at the old heap end may be too small or misaligned,
+        // so request enough room for the whole layout
+        let grown = super::grow_heap(self.fallback_allocator.top(), layout.size() + layout.align());
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe {
+            self.fallback_allocator.extend(grown);
+        }
+        self.fallback_allocator.allocate(layout)
+    }
+
+    /// Allocates a block of the given block size from a slab.
+    ///
+    /// Return a pointer to the block or null if no new slab could be allocated.
+    fn alloc_block(&mut self, index: usize) -> *mut u8 {
+        let slab = match self.partial_slabs[index] {
+            Some(slab) => slab,
+            None => match self.new_slab(index) {
+                Some(slab) => slab,
+                None => return ptr::null_mut(),
+            },
+        };
+        let slab_ref = unsafe { &mut *slab.as_ptr() };
+        let node = slab_ref.free_list.take().expect("partial slab without free block");
+        slab_ref.free_list = node.next.take(); // update head to next node
+        slab_ref.in_use += 1;
+        if slab_ref.free_list.is_none() {
+            // slab is full now
+            unsafe { self.remove_partial(index, slab) };
+        }
+        self.free_blocks[index] -= 1;
+        node as *mut ListNode as *mut u8
+    }
+
+    /// Frees a block of the given block size.
+    ///
+    /// Gives the slab back to the fallback allocator once all of its blocks are
+    /// free, unless it is the only slab with free blocks left.
+    unsafe fn dealloc_block(&mut self, ptr: *mut u8, index: usize) {
+        // verify that the block has size and alignment required for storing a Node
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+        // slabs are aligned to their size, so the header is found by masking
+        let slab_addr = ptr as usize & !(slab_size(index) - 1);
+        let slab = NonNull::new(slab_addr as *mut Slab).unwrap();
+        let slab_ref = unsafe { &mut *slab.as_ptr() };
+        let was_full = slab_ref.free_list.is_none();
+
+        // write a new node into the freed block
+        let new_node_ptr = ptr as *mut ListNode;
+        unsafe {
+            new_node_ptr.write(ListNode {
+                next: slab_ref.free_list.take(),
+            });
+            slab_ref.free_list = Some(&mut *new_node_ptr);
+        }
+        slab_ref.in_use -= 1;
+        self.free_blocks[index] += 1;
+
+        if slab_ref.in_use == 0 {
+            let is_last = if was_full {
+                self.partial_slabs[index].is_none()
+            } else {
+                slab_ref.prev.is_none() && slab_ref.next.is_none()
+            };
+            if is_last {
+                // keep a single empty slab around
+                if was_full {
+                    unsafe { self.push_partial(index, slab) };
+                }
+            } else {
+                // slab is empty -> give it back to the fallback allocator
+                if !was_full {
+                    unsafe { self.remove_partial(index, slab) };
+                }
+                self.slabs[index] -= 1;
+                self.free_blocks[index] -= blocks_per_slab(index);
+                unsafe {
+                    self.fallback_allocator.deallocate(slab_addr as *mut u8, slab_layout(index));
+                }
+            }
+        } else if was_full {
+            // slab has a free block again
+            unsafe { self.push_partial(index, slab) };
+        }
+    }
+
+    /// Allocates a new slab for the given block size and cuts it into blocks.
+    ///
+    /// The new slab is added to the list of slabs with free blocks.
+    fn new_slab(&mut self, index: usize) -> Option<NonNull<Slab>> {
+        let slab_ptr = self.fallback_alloc(slab_layout(index));
+        let slab = NonNull::new(slab_ptr as *mut Slab)?;
+
+        // link all blocks behind the header into the free list
+        let block_size = BLOCK_SIZES[index];
+        let mut free_list = None;
+        for offset in (first_block_offset(index)..slab_size(index)).step_by(block_size).rev() {
+            let node_ptr = unsafe { slab_ptr.add(offset) } as *mut ListNode;
+            unsafe {
+                node_ptr.write(ListNode { next: free_list.take() });
+                free_list = Some(&mut *node_ptr);
+            }
+        }
+        unsafe {
+            slab.as_ptr().write(Slab {
+                free_list,
+                in_use: 0,
+                prev: None,
+                next: None,
+            });
+            self.push_partial(index, slab);
+        }
+        self.slabs[index] += 1;
+        self.free_blocks[index] += blocks_per_slab(index);
+        Some(slab)
+    }
+
+    /// Adds the slab to the front of the list of slabs with free blocks.
+    ///
+    /// # Safety
+    /// The slab must be valid and must not be in the list already.
+    unsafe fn push_partial(&mut self, index: usize, mut slab: NonNull<Slab>) {
+        let slab_ref = unsafe { slab.as_mut() };
+        slab_ref.prev = None;
+        slab_ref.next = self.partial_slabs[index];
+        if let Some(mut next) = slab_ref.next {
+            unsafe { next.as_mut().prev = Some(slab) };
         }
+        self.partial_slabs[index] = Some(slab);
     }
+
+    /// Removes the slab from the list of slabs with free blocks.
+    ///
+    /// # Safety
+    /// The slab must be valid and must be in the list.
+    unsafe fn remove_partial(&mut self, index: usize, slab: NonNull<Slab>) {
+        let (prev, next) = unsafe { (slab.as_ref().prev, slab.as_ref().next) };
+        match prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = next },
+            None => self.partial_slabs[index] = next,
+        }
+        if let Some(mut next) = next {
+            unsafe { next.as_mut().prev = prev };
+        }
+    }
+}
+
+/// Returns the size of the slabs for the given block size index.
+///
+/// Each slab holds at least a few blocks besides its header.
+fn slab_size(index: usize) -> usize {
+    (BLOCK_SIZES[index] * 8).max(MIN_SLAB_SIZE)
+}
+
+/// Returns the layout of the slabs for the given block size index.
+fn slab_layout(index: usize) -> Layout {
+    Layout::from_size_align(slab_size(index), slab_size(index)).unwrap()
+}
+
+/// Returns the offset of the first block behind the slab header.
+fn first_block_offset(index: usize) -> usize {
+    align_up(mem::size_of::<Slab>(), BLOCK_SIZES[index])
+}
+
+/// Returns the number of blocks in a slab for the given block size index.
+fn blocks_per_slab(index: usize) -> usize {
+    (slab_size(index) - first_block_offset(index)) / BLOCK_SIZES[index]
 }
 
 /// Choose an appropriate block size for the given layout.
@@ -83,24 +326,20 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         let mut allocator = self.lock(); // get a mutable reference
         match list_index(&layout) { // find suitable block size
             Some(index) => { // suitable block size found
-                match allocator.list_heads[index].take() { // take the head of the list
-                    Some(node) => { // block exists in list => use it
-                        allocator.list_heads[index] = node.next.take(); // update head to next node
-                        node as *mut ListNode as *mut u8 // return pointer to block
-                    }
-                    None => {
-                        // no block exists in list => allocate a new block
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are power of 2
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
+                let ptr = allocator.alloc_block(index);
+                if !ptr.is_null() {
+                    allocator.allocated_blocks[index] += 1;
+                    allocator.stats.record_alloc(BLOCK_SIZES[index]);
                 }
+                ptr
             }
             None => {
                 // not suitable block size found => use fallback allocator
-                allocator.fallback_alloc(layout)
+                let ptr = allocator.fallback_alloc(layout);
+                if !ptr.is_null() {
+                    allocator.stats.record_alloc(layout.size());
+                }
+                ptr
             }
         }
     }
@@ -113,27 +352,55 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         let mut allocator = self.lock(); // get a mutable reference
         match list_index(&layout) { // find suitable block size
             Some(index) => { // suitable block size found
-                // create a new node for the freed block
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // verify that the block has size and alignment required for storing a Node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                // write the new node into the freed block
-                let new_node_ptr = ptr as *mut ListNode;
                 unsafe {
-                    new_node_ptr.write(new_node);
-                    allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                    allocator.dealloc_block(ptr, index);
                 }
+                allocator.allocated_blocks[index] -= 1;
+                allocator.stats.record_dealloc(BLOCK_SIZES[index]);
             }
             None => {
                 // not suitable block size found => use fallback allocator
-                let ptr = NonNull::new(ptr).unwrap();
                 unsafe {
                     allocator.fallback_allocator.deallocate(ptr, layout);
                 }
+                allocator.stats.record_dealloc(layout.size());
             }        
         }
     }
-}
\ No newline at end of file
+
+    /// Resizes the memory at the given pointer to `new_size`.
+    ///
+    /// Blocks stay in place as long as the new size still fits the same block
+    /// size. Larger allocations are resized in place by the fallback allocator
+    /// if the free space behind them allows it. Otherwise the data is copied
+    /// to a new allocation.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let mut allocator = self.lock();
+        match (list_index(&layout), list_index(&new_layout)) {
+            (Some(index), Some(new_index)) if index == new_index => {
+                // block already has room for the new size
+                allocator.stats.in_place_reallocs += 1;
+                return ptr;
+            }
+            (None, None)
+                if unsafe { allocator.fallback_allocator.resize_in_place(ptr, layout, new_size) } =>
+            {
+                allocator.stats.record_realloc_in_place(layout.size(), new_size);
+                return ptr;
+            }
+            _ => {}
+        }
+        allocator.stats.moved_reallocs += 1;
+        drop(allocator);
+
+        // no room in place -> move the data to a new allocation
+        unsafe { super::realloc_by_copy(self, ptr, layout, new_size) }
+    }
+}
+
+impl HeapStatistics for Locked<FixedSizeBlockAllocator> {
+    fn stats(&self) -> HeapStats {
+        self.lock().stats()
+    }
+}
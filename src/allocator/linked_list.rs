@@ -2,7 +2,7 @@
 
 use super::align_up;
 use core::mem;
-use super::Locked;
+use super::{HeapStatistics, HeapStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -27,6 +27,9 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_start: usize,
+    heap_end: usize,
+    stats: HeapStats,
 }
 
 impl LinkedListAllocator {
@@ -34,15 +37,142 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_start: 0,
+            heap_end: 0,
+            stats: HeapStats::empty(),
         }
     }
 
     // init allocator with given heap bounds
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.add_free_region(heap_start, heap_size);
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        unsafe {
+            self.add_free_region(heap_start, heap_size);
+        }
+    }
+
+    // extends the heap by 'by' bytes at its current end
+    //
+    // unsafe because the caller must guarantee that the memory after the heap
+    // end is mapped and unused
+    pub unsafe fn extend(&mut self, by: usize) {
+        unsafe {
+            self.add_free_region(self.heap_end, by);
+        }
+        self.heap_end += by;
+    }
+
+    // size of the heap in bytes
+    pub fn size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+
+    // end address of the heap
+    pub fn top(&self) -> usize {
+        self.heap_end
+    }
+
+    // allocates a memory block with the given layout
+    //
+    // returns a null pointer if no free region is large enough
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        // perform layout adjustments
+        let (size, align) = Self::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            let alloc_end = alloc_start.checked_add(size).expect("overflow in alloc");
+            // give back the unused space in front of and behind the allocation
+            if alloc_start > region_start {
+                unsafe {
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+            }
+            if region_end > alloc_end {
+                unsafe {
+                    self.add_free_region(alloc_end, region_end - alloc_end);
+                }
+            }
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut() // no suitable region found
+        }
     }
 
-    // adds the given memory region to the front of the list
+    // frees the memory block at 'ptr' that was allocated with 'layout'
+    //
+    // the block must have been returned by 'allocate' with the same layout
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        // perform layout adjustments
+        let (size, _) = Self::size_align(layout);
+
+        unsafe {
+            self.add_free_region(ptr as usize, size);
+        }
+    }
+
+    // tries to resize the block at 'ptr' to 'new_size' without moving it
+    //
+    // shrinking gives the tail back to the free list, growing takes the
+    // missing bytes from a free region that starts right at the block end.
+    // Returns false if that is not possible
+    //
+    // the block must have been returned by 'allocate' with 'layout'
+    pub unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let (old_size, _) = Self::size_align(layout);
+        let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+            Ok(layout) => layout,
+            Err(_) => return false,
+        };
+        let (new_size, _) = Self::size_align(new_layout);
+        let block_start = ptr as usize;
+        let block_end = block_start + old_size;
+        let new_end = block_start + new_size;
+
+        if new_size <= old_size {
+            let excess_size = old_size - new_size;
+            if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+                // tail too small to hold a ListNode
+                return false;
+            }
+            if excess_size > 0 {
+                unsafe {
+                    self.add_free_region(new_end, excess_size);
+                }
+            }
+            return true;
+        }
+
+        // find the free region that directly follows the block
+        let mut current = &mut self.head;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < block_end) {
+            current = current.next.as_mut().unwrap();
+        }
+        let following = current.next.take_if(|next| {
+            next.start_addr() == block_end
+                && (next.end_addr() == new_end
+                    || next.end_addr() >= new_end + mem::size_of::<ListNode>())
+        });
+        let Some(following) = following else {
+            return false; // no free neighbour or too small
+        };
+        let region_end = following.end_addr();
+        current.next = following.next.take();
+
+        if region_end > new_end {
+            unsafe {
+                self.add_free_region(new_end, region_end - new_end);
+            }
+        }
+        true
+    }
+
+    // adds the given memory region to the list
+    //
+    // the list is kept sorted by address and the region is merged with
+    // directly adjacent free regions, so freed memory does not fragment
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         //ensure that the freed region is capable of holding a ListNode
         // align the start address
@@ -50,13 +180,35 @@ impl LinkedListAllocator {
         // ensure the size is big enough
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        unsafe {
-            node_ptr.write(node);
-            self.head.next = Some(&mut *node_ptr);
+        // find the last region that starts before the new one
+        let mut current = &mut self.head;
+        let mut current_is_head = true;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+            current_is_head = false;
+        }
+
+        // merge with the following region if it starts right at the end
+        let mut size = size;
+        let mut next = current.next.take();
+        if let Some(following) = next.take_if(|next| next.start_addr() == addr + size) {
+            size += following.size;
+            next = following.next.take();
+        }
+
+        if !current_is_head && current.end_addr() == addr {
+            // merge with the preceding region
+            current.size += size;
+            current.next = next;
+        } else {
+            // create a new list node and insert it after the preceding region
+            let mut node = ListNode::new(size);
+            node.next = next;
+            let node_ptr = addr as *mut ListNode;
+            unsafe {
+                node_ptr.write(node);
+                current.next = Some(&mut *node_ptr);
+            }
         }
     }
 
@@ -91,7 +243,12 @@ impl LinkedListAllocator {
         fn alloc_from_region(region: &ListNode, size: usize, align: usize)
             -> Result<usize, ()> {
 
-                let alloc_start = align_up(region.start_addr(), align);
+                let mut alloc_start = align_up(region.start_addr(), align);
+                let padding = alloc_start - region.start_addr();
+                if padding > 0 && padding < mem::size_of::<ListNode>() {
+                    // space in front too small to hold a ListNode -> move further
+                    alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+                }
                 let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
                 if alloc_end > region.end_addr() {
@@ -109,6 +266,14 @@ impl LinkedListAllocator {
                 Ok(alloc_start)
             }
 
+            // returns the usage statistics, counting the adjusted allocation sizes
+            pub fn stats(&self) -> HeapStats {
+                HeapStats {
+                    heap_size: self.size(),
+                    ..self.stats
+                }
+            }
+
             // adjust the given layout so thath the resulting allocated memory
             // region is also capable of holding a ListNode
             //
@@ -126,32 +291,43 @@ impl LinkedListAllocator {
 
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // perform layout adjustments
-        let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
-
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow in alloc");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                unsafe {
-                    allocator.add_free_region(alloc_end, excess_size);
-                }
-            }
-            alloc_start as *mut u8
-        } else {
-            ptr::null_mut() // no suitable region found
+        let ptr = allocator.allocate(layout);
+        if !ptr.is_null() {
+            let (size, _) = LinkedListAllocator::size_align(layout);
+            allocator.stats.record_alloc(size);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-
-        // perform layout adjustments
+        let mut allocator = self.lock();
+        unsafe {
+            allocator.deallocate(ptr, layout);
+        }
         let (size, _) = LinkedListAllocator::size_align(layout);
+        allocator.stats.record_dealloc(size);
+    }
 
-        unsafe {
-            self.lock().add_free_region(ptr as usize, size);
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let mut allocator = self.lock();
+        if unsafe { allocator.resize_in_place(ptr, layout, new_size) } {
+            let (old_size, _) = LinkedListAllocator::size_align(layout);
+            let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+            let (new_size, _) = LinkedListAllocator::size_align(new_layout);
+            allocator.stats.record_realloc_in_place(old_size, new_size);
+            return ptr;
         }
+        allocator.stats.moved_reallocs += 1;
+        drop(allocator);
+
+        // no room in place -> move the block
+        unsafe { super::realloc_by_copy(self, ptr, layout, new_size) }
+    }
+}
 
+impl HeapStatistics for Locked<LinkedListAllocator> {
+    fn stats(&self) -> HeapStats {
+        self.lock().stats()
     }
 }
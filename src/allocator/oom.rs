@@ -0,0 +1,113 @@
+// src/allocator/oom.rs
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+// a function that tries to free heap memory, e.g. by flushing a cache
+//
+// gets the layout that could not be allocated and returns whether it freed
+// any memory. Hooks must not rely on allocating memory themselves
+pub type ReclaimHook = fn(Layout) -> bool;
+
+// maximum number of reclaim hooks that can be registered
+//
+// the hooks are stored in a fixed table because they run when the heap is full
+const MAX_RECLAIM_HOOKS: usize = 8;
+
+static RECLAIM_HOOKS: Mutex<[Option<ReclaimHook>; MAX_RECLAIM_HOOKS]> =
+    Mutex::new([None; MAX_RECLAIM_HOOKS]);
+
+// set while the reclaim hooks run, so that an allocation failing inside a
+// hook does not run the hooks again
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+// error returned when the reclaim hook table is full
+#[derive(Debug)]
+pub struct TooManyReclaimHooks;
+
+// registers a hook that is run before an allocation fails
+pub fn register_reclaim_hook(hook: ReclaimHook) -> Result<(), TooManyReclaimHooks> {
+    let mut hooks = RECLAIM_HOOKS.lock();
+    let slot = hooks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(TooManyReclaimHooks)?;
+    *slot = Some(hook);
+    Ok(())
+}
+
+// runs all registered reclaim hooks once
+//
+// returns whether any of them freed memory
+fn reclaim(layout: Layout) -> bool {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+    // copy the table so that hooks can free memory without holding the lock
+    let hooks = *RECLAIM_HOOKS.lock();
+    let mut freed = false;
+    for hook in hooks.into_iter().flatten() {
+        freed |= hook(layout);
+    }
+    RECLAIMING.store(false, Ordering::Release);
+    freed
+}
+
+// the global allocator of the kernel
+//
+// forwards to the allocator chosen by the 'alloc-*' features. When that one
+// runs out of memory, the registered reclaim hooks get a chance to free memory
+// and the allocation is retried once. If it still fails, the allocation error
+// handler reports the failing layout
+pub struct ReclaimingAllocator {
+    inner: &'static (dyn GlobalAlloc + Sync),
+}
+
+impl ReclaimingAllocator {
+    // creates a reclaiming allocator that forwards to 'inner'
+    pub const fn new(inner: &'static (dyn GlobalAlloc + Sync)) -> Self {
+        ReclaimingAllocator { inner }
+    }
+}
+
+unsafe impl GlobalAlloc for ReclaimingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if ptr.is_null() && reclaim(layout) {
+            return unsafe { self.inner.alloc(layout) };
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        if new_ptr.is_null() {
+            let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+            if reclaim(new_layout) {
+                return unsafe { self.inner.realloc(ptr, layout, new_size) };
+            }
+        }
+        new_ptr
+    }
+}
+
+// reports a failed allocation over serial and panics
+//
+// called through the allocation error handler in 'lib.rs'
+pub fn alloc_error(layout: Layout) -> ! {
+    use crate::serial_println;
+
+    serial_println!("ALLOCATION ERROR: could not allocate {:?}", layout);
+    if let Some(task_id) = crate::task::current_task_id() {
+        serial_println!("in task {:?}", task_id);
+    } else {
+        serial_println!("outside of any task");
+    }
+    super::print_heap_report();
+    panic!("allocation error: {:?}", layout);
+}
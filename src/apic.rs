@@ -0,0 +1,307 @@
+// src/apic.rs
+
+use crate::acpi::{self, Madt};
+use crate::memory::{self, region::RegionError};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::{
+    instructions::port::Port,
+    registers::model_specific::Msr,
+    structures::paging::PageTableFlags,
+    PhysAddr, VirtAddr,
+};
+
+// vector of the spurious interrupts of the local APIC. They need no end of interrupt
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// frequency of the local APIC timer, the scheduler tick
+pub const TICK_HZ: u32 = 100;
+
+// local APIC registers, offsets from its base address
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SPURIOUS: u64 = 0xf0;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL: u64 = 0x380;
+const LAPIC_TIMER_CURRENT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3e0;
+
+const LAPIC_ENABLE: u32 = 1 << 8;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// I/O APIC registers, selected through IOREGSEL and accessed through IOWIN
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+// error returned when the APICs cannot be used
+#[derive(Debug)]
+pub enum ApicError {
+    // the CPU has no local APIC
+    NotSupported,
+    // there is no ACPI MADT that describes the interrupt controllers
+    NoMadt,
+    // the MADT lists no I/O APIC
+    NoIoApic,
+    // the kernel memory is not set up yet, so the registers cannot be mapped
+    NoKernelMemory,
+    // mapping the registers failed
+    Map(RegionError),
+}
+
+// virtual address of the local APIC registers, 0 while the APICs are not in use
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+// the I/O APIC and how the ISA lines are connected to it
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    // number of redirection entries
+    entries: u32,
+    madt: Madt,
+}
+
+static IO_APIC: spin::Mutex<Option<IoApic>> = spin::Mutex::new(None);
+
+// returns whether the CPU has a local APIC
+pub fn supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    __cpuid(1).edx & (1 << 9) != 0
+}
+
+// returns whether interrupts are delivered through the APICs
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Relaxed) != 0
+}
+
+// finds the local APIC and the I/O APIC, maps their registers and enables
+// the local APIC
+//
+// all redirection entries of the I/O APIC are masked. The 8259 PICs are left
+// alone. Needs the kernel memory. 'interrupts::init_apic' switches the kernel
+// over to the APICs
+pub fn init() -> Result<(), ApicError> {
+    if !supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let phys_offset = memory::with_kernel_memory(|m| m.mapper.phys_offset())
+        .ok_or(ApicError::NoKernelMemory)?;
+    let madt = acpi::find_madt(phys_offset).ok_or(ApicError::NoMadt)?;
+    let io_apic = madt.io_apic.ok_or(ApicError::NoIoApic)?;
+
+    // the base in the MSR wins over the one in the MADT
+    let mut apic_base_msr = Msr::new(IA32_APIC_BASE);
+    let apic_base = unsafe { apic_base_msr.read() };
+    let local_apic_addr = match apic_base & 0x000f_ffff_ffff_f000 {
+        0 => madt.local_apic_addr,
+        addr => PhysAddr::new(addr),
+    };
+    let local_apic = map_registers(local_apic_addr)?;
+    let io_apic_base = map_registers(io_apic.addr)?;
+    unsafe { apic_base_msr.write(apic_base | APIC_BASE_ENABLE) };
+
+    let entries = (read_io_apic(io_apic_base, IOAPIC_VERSION) >> 16 & 0xff) + 1;
+    for entry in 0..entries {
+        write_redirection(io_apic_base, entry, REDIRECTION_MASKED);
+    }
+    *IO_APIC.lock() = Some(IoApic { base: io_apic_base, gsi_base: io_apic.gsi_base, entries, madt });
+
+    unsafe { write_local_apic(local_apic, LAPIC_SPURIOUS, LAPIC_ENABLE | u32::from(SPURIOUS_VECTOR)) };
+    LOCAL_APIC.store(local_apic.as_u64(), Ordering::Relaxed);
+    Ok(())
+}
+
+// maps the 4 KiB of registers at 'phys' uncached
+fn map_registers(phys: PhysAddr) -> Result<VirtAddr, ApicError> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    let region = memory::with_kernel_memory(|m| m.map_mmio(phys, 4096, flags))
+        .ok_or(ApicError::NoKernelMemory)?
+        .map_err(ApicError::Map)?;
+    // the region starts at the page that contains 'phys'
+    Ok(region.start + (phys.as_u64() & 0xfff))
+}
+
+// signals the end of an interrupt to the local APIC
+pub fn end_of_interrupt() {
+    let base = LOCAL_APIC.load(Ordering::Relaxed);
+    if base != 0 {
+        unsafe { write_local_apic(VirtAddr::new(base), LAPIC_EOI, 0) };
+    }
+}
+
+// returns the ID of the local APIC of the current CPU
+pub fn local_apic_id() -> Option<u8> {
+    let base = LOCAL_APIC.load(Ordering::Relaxed);
+    if base == 0 {
+        return None;
+    }
+    Some((unsafe { read_local_apic(VirtAddr::new(base), LAPIC_ID) } >> 24) as u8)
+}
+
+// routes the ISA interrupt 'irq' to 'vector' on the current CPU
+//
+// interrupt source overrides from the MADT are applied. If 'masked' is set,
+// the entry is programmed but no interrupts are delivered.
+// Returns false if the APICs are not in use or the I/O APIC does not handle the line
+pub fn route_isa_irq(irq: u8, vector: u8, masked: bool) -> bool {
+    let Some(destination) = local_apic_id() else {
+        return false;
+    };
+    let io_apic = IO_APIC.lock();
+    let Some(io_apic) = io_apic.as_ref() else {
+        return false;
+    };
+    // e.g. the PIT is usually redirected to the line of the cascade, which must not overwrite it
+    if io_apic.madt.isa_irq_replaced(irq) {
+        return false;
+    }
+    let isa_irq = io_apic.madt.isa_irq(irq);
+    let Some(entry) = isa_irq.gsi.checked_sub(io_apic.gsi_base).filter(|&e| e < io_apic.entries) else {
+        return false;
+    };
+
+    let mut value = u64::from(vector) | u64::from(destination) << 56;
+    if isa_irq.active_low {
+        value |= REDIRECTION_ACTIVE_LOW;
+    }
+    if isa_irq.level_triggered {
+        value |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    if masked {
+        value |= REDIRECTION_MASKED;
+    }
+    write_redirection(io_apic.base, entry, value);
+    true
+}
+
+// returns the redirection entry the ISA interrupt 'irq' is routed through
+pub fn isa_irq_redirection(irq: u8) -> Option<u64> {
+    let io_apic = IO_APIC.lock();
+    let io_apic = io_apic.as_ref()?;
+    let entry = io_apic.madt.isa_irq(irq).gsi.checked_sub(io_apic.gsi_base)?;
+    (entry < io_apic.entries).then(|| read_redirection(io_apic.base, entry))
+}
+
+// starts the local APIC timer with 'TICK_HZ' interrupts per second on 'vector'
+//
+// the timer is calibrated against the PIT first, which takes 10 ms. Returns
+// false if the APICs are not in use or the calibration failed, in which case
+// the PIT has to stay the timer
+pub fn start_timer(vector: u8) -> bool {
+    let base = LOCAL_APIC.load(Ordering::Relaxed);
+    if base == 0 {
+        return false;
+    }
+    let base = VirtAddr::new(base);
+    unsafe {
+        write_local_apic(base, LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        let Some(ticks_per_10ms) = calibrate_timer(base) else {
+            return false;
+        };
+        let ticks_per_second = ticks_per_10ms.saturating_mul(100);
+        write_local_apic(base, LAPIC_LVT_TIMER, u32::from(vector) | TIMER_PERIODIC);
+        write_local_apic(base, LAPIC_TIMER_INITIAL, ticks_per_second / TICK_HZ);
+    }
+    TIMER_RUNNING.store(true, Ordering::Relaxed);
+    true
+}
+
+// whether the local APIC timer was started and replaces the PIT
+static TIMER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+// returns whether the local APIC timer replaces the PIT
+pub fn timer_running() -> bool {
+    TIMER_RUNNING.load(Ordering::Relaxed)
+}
+
+// frequency of the PIT input clock in Hz
+const PIT_FREQUENCY: u32 = 1_193_182;
+
+// fewest local APIC timer ticks in 10 ms that are believed, even a slow
+// 1.6 MHz timer clock reaches this
+const MIN_CALIBRATION_TICKS: u32 = 1000;
+
+// returns how far the local APIC timer counts in 10 ms
+//
+// measures the time with a countdown of PIT channel 2, which raises no
+// interrupt and can be polled through port 0x61. Returns None if the result
+// is implausible, e.g. because the PIT output never changed
+unsafe fn calibrate_timer(base: VirtAddr) -> Option<u32> {
+    let mut control: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    let count = PIT_FREQUENCY / 100;
+
+    let elapsed = unsafe {
+        // gate of channel 2 off while it is programmed, speaker off
+        let value = control.read() & !0b11;
+        control.write(value);
+        // channel 2, low and high byte, mode 0 (interrupt on terminal count):
+        // the output goes low with the command and high when the count runs out
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        // the gate starts the countdown
+        control.write(value | 0b01);
+        write_local_apic(base, LAPIC_TIMER_INITIAL, u32::MAX);
+
+        // wait for the output of channel 2 to go high, but not longer than
+        // the local APIC timer can count
+        while control.read() & 0b10_0000 == 0 && read_local_apic(base, LAPIC_TIMER_CURRENT) != 0 {
+            core::hint::spin_loop();
+        }
+        let elapsed = u32::MAX - read_local_apic(base, LAPIC_TIMER_CURRENT);
+        write_local_apic(base, LAPIC_TIMER_INITIAL, 0);
+        control.write(value);
+        elapsed
+    };
+    (MIN_CALIBRATION_TICKS..u32::MAX).contains(&elapsed).then_some(elapsed)
+}
+
+unsafe fn read_local_apic(base: VirtAddr, register: u64) -> u32 {
+    unsafe { (base + register).as_ptr::<u32>().read_volatile() }
+}
+
+unsafe fn write_local_apic(base: VirtAddr, register: u64, value: u32) {
+    unsafe { (base + register).as_mut_ptr::<u32>().write_volatile(value) }
+}
+
+fn read_io_apic(base: VirtAddr, register: u32) -> u32 {
+    unsafe {
+        (base + IOREGSEL).as_mut_ptr::<u32>().write_volatile(register);
+        (base + IOWIN).as_ptr::<u32>().read_volatile()
+    }
+}
+
+fn write_io_apic(base: VirtAddr, register: u32, value: u32) {
+    unsafe {
+        (base + IOREGSEL).as_mut_ptr::<u32>().write_volatile(register);
+        (base + IOWIN).as_mut_ptr::<u32>().write_volatile(value);
+    }
+}
+
+fn read_redirection(base: VirtAddr, entry: u32) -> u64 {
+    let register = IOAPIC_REDIRECTION_TABLE + 2 * entry;
+    u64::from(read_io_apic(base, register)) | u64::from(read_io_apic(base, register + 1)) << 32
+}
+
+fn write_redirection(base: VirtAddr, entry: u32, value: u64) {
+    let register = IOAPIC_REDIRECTION_TABLE + 2 * entry;
+    // mask the entry while it is half written
+    write_io_apic(base, register, REDIRECTION_MASKED as u32);
+    write_io_apic(base, register + 1, (value >> 32) as u32);
+    write_io_apic(base, register, value as u32);
+}
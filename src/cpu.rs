@@ -0,0 +1,140 @@
+// src/cpu.rs
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+// protection features of the CPU that keep the kernel away from user memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    // supervisor mode execution prevention: the kernel cannot execute user pages
+    pub smep: bool,
+    // supervisor mode access prevention: the kernel cannot access user pages
+    // unless it explicitly allows it with 'stac'
+    pub smap: bool,
+    // user mode instruction prevention: user code cannot read descriptor table
+    // registers, e.g. with 'sgdt'
+    pub umip: bool,
+}
+
+impl Protection {
+    // detects which protection features the CPU supports
+    pub fn supported() -> Self {
+        // structured extended feature flags
+        let max_leaf = __cpuid_count(0, 0).eax;
+        if max_leaf < 7 {
+            return Protection { smep: false, smap: false, umip: false };
+        }
+        let features = __cpuid_count(7, 0);
+        Protection {
+            smep: features.ebx & (1 << 7) != 0,
+            smap: features.ebx & (1 << 20) != 0,
+            umip: features.ecx & (1 << 2) != 0,
+        }
+    }
+
+    // returns the protection features that are turned on in CR4
+    pub fn enabled() -> Self {
+        let cr4 = Cr4::read();
+        Protection {
+            smep: cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+            smap: cr4.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+            umip: cr4.contains(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION),
+        }
+    }
+}
+
+// whether SMAP is on, in which case user accesses need 'stac' and 'clac'
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// turns on all protection features the CPU supports
+//
+// returns the features that were turned on
+pub fn init_protection() -> Protection {
+    let supported = Protection::supported();
+    let mut flags = Cr4Flags::empty();
+    if supported.smep {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if supported.smap {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    if supported.umip {
+        flags |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+    }
+    unsafe { Cr4::update(|cr4| *cr4 |= flags) };
+    SMAP_ENABLED.store(supported.smap, Ordering::Relaxed);
+    supported
+}
+
+// --- access to user memory ---
+
+// first address of user space
+//
+// the kernel is not in the upper half, so user space is the top quarter of
+// the lower half, which the kernel never maps (checked by
+// 'memory::init_kernel_memory'). The heap, the kernel regions, the kernel image
+// and the physical memory mapping all lie below it
+pub const USER_SPACE_START: u64 = 0x0000_6000_0000_0000;
+
+// first address that does not belong to user space, the start of the
+// non-canonical hole
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+// error returned by the user memory accessors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAccessError {
+    // the range does not lie completely in user space, e.g. it points into the kernel
+    NotUserAddress,
+}
+
+// allows the kernel to access user pages while it exists
+//
+// executes 'stac' when created and 'clac' when dropped if SMAP is enabled
+struct UserAccessGuard;
+
+impl UserAccessGuard {
+    fn new() -> Self {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        UserAccessGuard
+    }
+}
+
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
+
+fn check_user_range(addr: *const u8, len: usize) -> Result<(), UserAccessError> {
+    let start = addr as u64;
+    match start.checked_add(len as u64) {
+        Some(end) if start >= USER_SPACE_START && end <= USER_SPACE_END => Ok(()),
+        _ => Err(UserAccessError::NotUserAddress),
+    }
+}
+
+// copies 'dst.len()' bytes from user memory at 'src' into 'dst'
+//
+// the user range must be mapped in the active address space
+pub unsafe fn copy_from_user(dst: &mut [u8], src: *const u8) -> Result<(), UserAccessError> {
+    check_user_range(src, dst.len())?;
+    let _guard = UserAccessGuard::new();
+    unsafe { core::ptr::copy_nonoverlapping(src, dst.as_mut_ptr(), dst.len()) };
+    Ok(())
+}
+
+// copies 'src' into user memory at 'dst'
+//
+// the user range must be mapped writable in the active address space
+pub unsafe fn copy_to_user(dst: *mut u8, src: &[u8]) -> Result<(), UserAccessError> {
+    check_user_range(dst, src.len())?;
+    let _guard = UserAccessGuard::new();
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len()) };
+    Ok(())
+}
@@ -12,20 +12,21 @@ use x86_64::structures::gdt::SegmentSelector;
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
 
-lazy_static! { // Initialize the Task State Segment (TSS)
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new(); // Create a new TSS
-        // Set up the stack for double fault exceptions
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = { // Allocate stack for double fault handler
-            const STAKC_SIZE: usize = 4096 * 5; // 20 KB stack
-            static mut STACK: [u8; STAKC_SIZE] = [0; STAKC_SIZE]; // Static stack allocation
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK); // Get starting virtual address of the stack
-            let stack_end = stack_start + STAKC_SIZE; // Calculate the end address of the stack
-            stack_end // Return the top of the stack (stacks grow downwards)
-        };
-        tss // Return the initialized TSS
-    };
+// Size of the kernel stacks used by the Interrupt Stack Table
+const IST_STACK_SIZE: u64 = 4096 * 5; // 20 KB stack
+
+// The Task State Segment (TSS)
+// mutable because the IST stacks are replaced once kernel memory is available
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+// Stack for double faults until init_stacks is called
+// it has no guard page, so it is only used during early boot
+fn early_double_fault_stack() -> VirtAddr {
+    const STAKC_SIZE: usize = IST_STACK_SIZE as usize;
+    static mut STACK: [u8; STAKC_SIZE] = [0; STAKC_SIZE]; // Static stack allocation
+
+    let stack_start = VirtAddr::from_ptr(&raw const STACK); // Get starting virtual address of the stack
+    stack_start + STAKC_SIZE // Return the top of the stack (stacks grow downwards)
 }
 
 lazy_static! {
@@ -35,7 +36,10 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new(); // Create a new GDT
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment()); // Add kernel code segment
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS)); // Add TSS segment
+        // the TSS is only changed through raw pointers afterwards
+        let tss_ptr = &raw const TSS;
+        let tss = unsafe { &*tss_ptr };
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss)); // Add TSS segment
         (gdt, Selectors { code_selector, tss_selector }) // Return the GDT and selectors
     };
 }
@@ -53,9 +57,26 @@ pub fn init() {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::{CS, Segment};
 
+    let tss = &raw mut TSS;
+    unsafe {
+        (*tss).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = early_double_fault_stack();
+    }
     GDT.0.load(); // Load the GDT
     unsafe {
         CS::set_reg(GDT.1.code_selector);  // Set the code segment register
         load_tss(GDT.1.tss_selector); // Load the TSS
     }
-}
\ No newline at end of file
+}
+
+// Replace the IST stacks with stacks from the kernel address space,
+// each with an unmapped guard page below it
+// memory::init_kernel_memory must be called before
+pub fn init_stacks() {
+    let stack = crate::memory::with_kernel_memory(|memory| memory.map_stack("double fault", IST_STACK_SIZE))
+        .expect("kernel memory not initialized")
+        .expect("failed to map double fault stack");
+    let tss = &raw mut TSS;
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*tss).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack.end();
+    });
+}
@@ -8,19 +8,39 @@ use lazy_static::lazy_static;
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(virtualization_handler);
+        idt.cp_protection_exception.set_handler_fn(cp_protection_handler);
+        idt.hv_injection_exception.set_handler_fn(hv_injection_handler);
+        idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+        idt.security_exception.set_handler_fn(security_exception_handler);
 
 
-        // Hardware Interrupts
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        // Hardware Interrupts, passed on to the handlers registered for their line
+        for (line, entry) in IRQ_ENTRIES.iter().enumerate() {
+            idt[usize::from(PIC_1_OFFSET) + line].set_handler_fn(*entry);
+        }
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -35,8 +55,129 @@ extern "x86-interrupt" fn breakpoint_handler(
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+// --- cpu exceptions ---
+
+// names of the exception vectors, reserved vectors have none
+const EXCEPTION_NAMES: [Option<&str>; 32] = [
+    Some("DIVIDE ERROR"),
+    Some("DEBUG"),
+    Some("NON-MASKABLE INTERRUPT"),
+    Some("BREAKPOINT"),
+    Some("OVERFLOW"),
+    Some("BOUND RANGE EXCEEDED"),
+    Some("INVALID OPCODE"),
+    Some("DEVICE NOT AVAILABLE"),
+    Some("DOUBLE FAULT"),
+    None,
+    Some("INVALID TSS"),
+    Some("SEGMENT NOT PRESENT"),
+    Some("STACK-SEGMENT FAULT"),
+    Some("GENERAL PROTECTION FAULT"),
+    Some("PAGE FAULT"),
+    None,
+    Some("X87 FLOATING-POINT EXCEPTION"),
+    Some("ALIGNMENT CHECK"),
+    Some("MACHINE CHECK"),
+    Some("SIMD FLOATING-POINT EXCEPTION"),
+    Some("VIRTUALIZATION EXCEPTION"),
+    Some("CONTROL PROTECTION EXCEPTION"),
+    None, None, None, None, None, None,
+    Some("HYPERVISOR INJECTION EXCEPTION"),
+    Some("VMM COMMUNICATION EXCEPTION"),
+    Some("SECURITY EXCEPTION"),
+    None,
+];
+
+// returns the name of the cpu exception with the given vector
+pub fn exception_name(vector: u8) -> Option<&'static str> {
+    EXCEPTION_NAMES.get(usize::from(vector)).copied().flatten()
+}
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const NO_EXCEPTION: u8 = u8::MAX;
+
+// vector of the last exception the kernel could not recover from
+static LAST_EXCEPTION: AtomicU8 = AtomicU8::new(NO_EXCEPTION);
+
+// returns the vector of the last exception the kernel could not recover from,
+// e.g. to find out in a panic handler what caused the panic
+pub fn last_exception() -> Option<u8> {
+    match LAST_EXCEPTION.load(Ordering::Relaxed) {
+        NO_EXCEPTION => None,
+        vector => Some(vector),
+    }
+}
+
+// report an exception the kernel cannot recover from and panic
+fn fatal_exception(vector: u8, error_code: Option<u64>, stack_frame: &InterruptStackFrame) -> ! {
+    LAST_EXCEPTION.store(vector, Ordering::Relaxed);
+    let name = exception_name(vector).unwrap_or("RESERVED");
+    match error_code {
+        Some(code) => panic!("EXCEPTION: {}\nError Code: {:#x}\n{:#?}", name, code, stack_frame),
+        None => panic!("EXCEPTION: {}\n{:#?}", name, stack_frame),
+    }
+}
+
+// define a handler that reports the exception with the given vector as fatal
+macro_rules! fatal_exception_handler {
+    ($handler:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            fatal_exception($vector, None, &stack_frame);
+        }
+    };
+    ($handler:ident, $vector:expr, error_code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            fatal_exception($vector, Some(error_code), &stack_frame);
+        }
+    };
+}
+
+fatal_exception_handler!(divide_error_handler, 0);
+fatal_exception_handler!(bound_range_exceeded_handler, 5);
+fatal_exception_handler!(invalid_opcode_handler, 6);
+fatal_exception_handler!(device_not_available_handler, 7);
+fatal_exception_handler!(invalid_tss_handler, 10, error_code);
+fatal_exception_handler!(segment_not_present_handler, 11, error_code);
+fatal_exception_handler!(stack_segment_fault_handler, 12, error_code);
+fatal_exception_handler!(general_protection_fault_handler, 13, error_code);
+fatal_exception_handler!(x87_floating_point_handler, 16);
+fatal_exception_handler!(alignment_check_handler, 17, error_code);
+fatal_exception_handler!(simd_floating_point_handler, 19);
+fatal_exception_handler!(virtualization_handler, 20);
+fatal_exception_handler!(cp_protection_handler, 21, error_code);
+fatal_exception_handler!(hv_injection_handler, 28);
+fatal_exception_handler!(vmm_communication_handler, 29, error_code);
+fatal_exception_handler!(security_exception_handler, 30, error_code);
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    fatal_exception(18, None, &stack_frame);
+}
+
+// debug, nmi and overflow are reported after the instruction, so execution can go on
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+        use x86_64::registers::control::Cr2;
+
+        LAST_EXCEPTION.store(8, Ordering::Relaxed);
+
+        // a page fault on a stack guard page cannot push its stack frame,
+        // so stack overflows end up here
+        if let Some(name) = crate::memory::stack_guard_hit(Cr2::read()) {
+            panic!("EXCEPTION: DOUBLE FAULT\nstack overflow in {}\n{:#?}", name, stack_frame);
+        }
         panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -49,39 +190,32 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    // e.g. demand paging, the faulting instruction is run again
+    if crate::memory::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
+    if let Some(name) = crate::memory::stack_guard_hit(Cr2::read()) {
+        println!("stack overflow in {}", name);
+    }
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
     hlt_loop();
 }
 // Hardware Interrupts
 
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame,
-) 
-{
+fn timer_interrupt() {
     print!(".");
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame,)
-{
+fn keyboard_interrupt() {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
 }
 
 // --- setting up hardware interrupts (PIC = Programmable Interrupt Controller) ---
@@ -95,23 +229,145 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> = 
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+// number of interrupt lines of the two PICs
+pub const IRQ_LINES: u8 = 16;
+
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+// line of the primary PIC the secondary one is connected to
+const CASCADE_IRQ: u8 = 2;
+
+// maximum number of handlers that can share a line
+const MAX_HANDLERS_PER_IRQ: usize = 4;
+
+// a handler for a hardware interrupt line
+//
+// runs with interrupts disabled and must not block, the end of interrupt
+// is sent after all handlers of the line have run
+pub type IrqHandler = fn();
+
+static IRQ_HANDLERS: spin::Mutex<[[Option<IrqHandler>; MAX_HANDLERS_PER_IRQ]; IRQ_LINES as usize]> =
+    spin::Mutex::new([[None; MAX_HANDLERS_PER_IRQ]; IRQ_LINES as usize]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterIrqError {
+    // there is no such line
+    InvalidLine,
+    // the line already has MAX_HANDLERS_PER_IRQ handlers
+    TooManyHandlers,
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
+// run 'handler' whenever the interrupt 'line' is raised and unmask the line
+//
+// several handlers can share a line, they run in the order they were registered
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), RegisterIrqError> {
+    if line >= IRQ_LINES {
+        return Err(RegisterIrqError::InvalidLine);
     }
+    // the lock is also taken by the interrupt handlers
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = handlers[usize::from(line)]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegisterIrqError::TooManyHandlers)?;
+        *slot = Some(handler);
+        unmask_irq(line);
+        Ok(())
+    })
+}
+
+// mask all lines and register the handlers of the kernel itself
+// must be called after the PICs are initialized
+pub fn init_irqs() {
+    unsafe { PICS.lock().write_masks(0xff, 0xff) };
+    register_irq(TIMER_IRQ, timer_interrupt).expect("failed to register timer interrupt");
+    register_irq(KEYBOARD_IRQ, keyboard_interrupt).expect("failed to register keyboard interrupt");
+}
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+fn unmask_irq(line: u8) {
+    if apic::is_enabled() {
+        // the local apic timer replaces the PIT, the cascade only exists between the PICs
+        let replaced = line == TIMER_IRQ && apic::timer_running();
+        if !replaced && line != CASCADE_IRQ {
+            apic::route_isa_irq(line, PIC_1_OFFSET + line, false);
+        }
+        return;
+    }
+    let mut pics = PICS.lock();
+    let mut masks = unsafe { pics.read_masks() };
+    masks[usize::from(line / 8)] &= !(1 << (line % 8));
+    if line >= 8 {
+        // lines of the secondary PIC arrive through the cascade line
+        masks[0] &= !(1 << CASCADE_IRQ);
     }
+    unsafe { pics.write_masks(masks[0], masks[1]) };
 }
 
+// run the handlers of 'line' and send the end of interrupt
+fn dispatch_irq(line: u8) {
+    // copy the handlers, so that they can register handlers themselves
+    let handlers = IRQ_HANDLERS.lock()[usize::from(line)];
+    for handler in handlers.into_iter().flatten() {
+        handler();
+    }
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(PIC_1_OFFSET + line);
+        }
+    }
+}
+
+// --- local APIC and I/O APIC ---
+
+use crate::apic;
+
+// deliver interrupts through the APICs instead of the 8259 PICs
+//
+// disables the PICs, routes every line that has handlers through the I/O APIC
+// and makes the local APIC timer raise the timer line. the PIT entry is
+// programmed but stays masked. needs the kernel memory, if the APICs cannot be
+// used the PICs stay in charge
+pub fn init_apic() -> Result<(), apic::ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        apic::init()?;
+        unsafe { PICS.lock().disable() };
+
+        // the PIT stays the timer if the local APIC timer cannot be calibrated
+        let local_timer = apic::start_timer(PIC_1_OFFSET + TIMER_IRQ);
+        let handlers = IRQ_HANDLERS.lock();
+        for line in (0..IRQ_LINES).filter(|&line| line != CASCADE_IRQ) {
+            let used = handlers[usize::from(line)].iter().any(Option::is_some);
+            let masked = !used || (line == TIMER_IRQ && local_timer);
+            apic::route_isa_irq(line, PIC_1_OFFSET + line, masked);
+        }
+        Ok(())
+    })
+}
+
+// spurious interrupts of the local apic are not real interrupts and need no end of interrupt
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+// define an interrupt handler for each line that calls dispatch_irq
+macro_rules! irq_entries {
+    ($($line:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn entry(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($line);
+            }
+            entry as HandlerFunc
+        }),*]
+    };
+}
+
+use x86_64::structures::idt::HandlerFunc;
+
+static IRQ_ENTRIES: [HandlerFunc; IRQ_LINES as usize] =
+    irq_entries!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+
 // --- test cases for interrupts ---
 
 #[test_case]
@@ -6,6 +6,10 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(allocator_api)]
+// safety requirements of unsafe functions are described in plain comments
+#![allow(clippy::missing_safety_doc)]
 
 extern crate alloc;
 
@@ -15,6 +19,9 @@ pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
 pub mod gdt;
+pub mod cpu;
+pub mod acpi;
+pub mod apic;
 pub mod memory;
 
 pub mod allocator;
@@ -95,11 +102,19 @@ fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info);
 }
 
+// Called when the global allocator fails, even after running the reclaim hooks
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    allocator::oom::alloc_error(layout)
+}
+
 // --- Section for interrupts ---
 
 pub fn init() {
     gdt::init();
+    cpu::init_protection();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize()};
+    interrupts::init_irqs();
     x86_64::instructions::interrupts::enable();
 }
\ No newline at end of file
@@ -40,7 +40,7 @@ entry_point!(kernel_main);
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Entry point of the program
     use capeos::memory;
-    use capeos::memory::BootInfoFrameAllocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
     use capeos::allocator;
     use x86_64::{VirtAddr};
 
@@ -51,15 +51,23 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     // init a mapper
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
+    // keep mapper and frame allocator around, e.g. for growing the heap
+    memory::init_kernel_memory(mapper, frame_allocator);
+    capeos::gdt::init_stacks();
 
     // initialize the heap
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    allocator::init_heap()
         .expect("heap initialization failed");
 
+    // switch to the local APIC and I/O APIC, the 8259 PICs are the fallback
+    if let Err(err) = capeos::interrupts::init_apic() {
+        println!("APIC not available ({:?}), using the 8259 PIC", err);
+    }
+
 
     let heap_value = Box::new(41);
     println!("heap_value at {:p}", heap_value);
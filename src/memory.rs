@@ -1,13 +1,27 @@
 // src/memory.rs
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
+    structures::idt::PageFaultErrorCode,
     structures::paging::{
-        FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize,
+        PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB
     },
     VirtAddr,
     PhysAddr,
 };
 
+pub mod address_space;
+pub mod bitmap;
+pub mod buddy;
+pub mod cow;
+pub mod region;
+pub mod walk;
+
+use buddy::BuddyFrameAllocator;
+use cow::FrameRefCounts;
+use region::{Region, RegionAllocator, RegionError, RegionKind};
+use walk::{MappedPageSize, PageTableWalker};
+
 // Returns mutable reference to the active lvl 4 page table 
 // The level 4 page table is the root of the paging hierarchy in x86_64 architecture
 
@@ -33,88 +47,551 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     // dereference the pointer to get a mutable reference to the PageTable
     unsafe { &mut *page_table_ptr }
 }
-/* 
-// translate a given virtual addresss to the mapped physical address or None if not mapped
 
-// this function is unsafe because the caller must guarantee that hte complete physical memory
-// is mapped to virtual memory at the passed offset
+// use x86_64 offset page table implementation
+// already supports huge pages and more features
+// memory::walk translates addresses and dumps the page tables
+
+// init new offsetpagetable
+
+// unsafe function bc caller must guarantee complete physical memory is mapped
+// only call once to avoid aliasing mutable references
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    unsafe {
+        let level_4_table = active_level_4_table(physical_memory_offset);
+        OffsetPageTable::new(level_4_table, physical_memory_offset)
+    }
+}
+
+// --- kernel memory kept after boot ---
+
+// page table mapper, frame allocator and address space regions that stay
+// available after boot, e.g. for growing the heap
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BuddyFrameAllocator,
+    pub regions: RegionAllocator,
+    // number of mappings of frames shared copy-on-write
+    pub frame_refs: FrameRefCounts,
+}
+
+impl KernelMemory {
+    // reserve a region of at least 'size' bytes and back it with new frames
+    //
+    // large regions are mapped with huge pages where possible
+    pub fn map_region(
+        &mut self,
+        size: u64,
+        kind: RegionKind,
+        flags: PageTableFlags,
+    ) -> Result<Region, RegionError> {
+        let region = self.regions.allocate(size, kind, flags)?;
+        if let Err(err) = self.map_range(region.start, None, region.size, flags) {
+            // give back everything mapped so far, the mapping error is the one
+            // the caller needs to see
+            let _ = self.unmap_region(region.start);
+            return Err(err.into());
+        }
+        Ok(region)
+    }
+
+    // reserve a region for 'size' bytes of device memory starting at 'phys'
+    // and map it to these frames
+    //
+    // the returned region starts at the page that contains 'phys'
+    pub fn map_mmio(
+        &mut self,
+        phys: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<Region, RegionError> {
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+        let size = size + (phys - first_frame.start_address());
+        let region = self.regions.allocate(size, RegionKind::Mmio, flags)?;
+        let phys = Some(first_frame.start_address());
+        if let Err(err) = self.map_range(region.start, phys, region.size, flags) {
+            let _ = self.unmap_region(region.start);
+            return Err(err.into());
+        }
+        Ok(region)
+    }
+
+    // reserve a region of at least 'size' bytes that is backed by zeroed frames
+    // only when its pages are first touched
+    //
+    // the region must not be accessed while the kernel memory is locked,
+    // because the page fault handler needs the lock
+    pub fn reserve_demand_zero(
+        &mut self,
+        size: u64,
+        kind: RegionKind,
+        flags: PageTableFlags,
+    ) -> Result<Region, RegionError> {
+        let region = self.regions.allocate(size, kind, flags)?;
+        self.regions.set_fault_handler(region.start, demand_zero_fault)?;
+        Ok(region)
+    }
+
+    // reserve a region for a kernel stack of at least 'size' bytes and map all
+    // but its lowest page, which stays unmapped as a guard page
+    //
+    // the stack grows down from the end of the region
+    pub fn map_stack(&mut self, name: &'static str, size: u64) -> Result<Region, RegionError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let region = self.regions.allocate(size + STACK_GUARD_SIZE, RegionKind::Stack(name), flags)?;
+        let stack_start = region.start + STACK_GUARD_SIZE;
+        if let Err(err) = self.map_range(stack_start, None, region.size - STACK_GUARD_SIZE, flags) {
+            let _ = self.unmap_region(region.start);
+            return Err(err.into());
+        }
+        Ok(region)
+    }
 
-pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
-    -> Option<PhysAddr>
+    // unmap the region starting at 'start' and give its frames back
+    // to the frame allocator, except for mmio regions
+    //
+    // pages of the region that are not mapped are skipped
+    pub fn unmap_region(&mut self, start: VirtAddr) -> Result<Region, RegionError> {
+        let region = self.regions.remove(start)?;
+        self.unmap_range(region.start, region.size, region.kind != RegionKind::Mmio);
+        Ok(region)
+    }
+
+    // map 'size' bytes starting at 'start', always using the largest page that fits
+    //
+    // if 'phys' is given, the range is mapped to the physical memory starting there,
+    // otherwise new frames are allocated. on error, the pages mapped so far stay mapped
+    pub fn map_range(
+        &mut self,
+        start: VirtAddr,
+        phys: Option<PhysAddr>,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let mut offset = 0;
+        while offset < size {
+            let phys = phys.map(|phys| phys + offset);
+            offset += self.map_largest_page(start + offset, phys, size - offset, flags)?;
+        }
+        Ok(())
+    }
+
+    // map a single page at 'addr' that is at most 'max_size' bytes large
+    //
+    // huge pages are used if 'addr' (and 'phys', if given) is aligned to them
+    // and the frame allocator has a frame of that size
+    // returns the size of the mapped page
+    pub fn map_largest_page(
+        &mut self,
+        addr: VirtAddr,
+        phys: Option<PhysAddr>,
+        max_size: u64,
+        flags: PageTableFlags,
+    ) -> Result<u64, MapToError<Size4KiB>> {
+        if supports_1gib_pages()
+            && let Some(result) = self.try_map_page::<Size1GiB>(addr, phys, max_size, flags)
+        {
+            return result.map(|()| Size1GiB::SIZE);
+        }
+        if let Some(result) = self.try_map_page::<Size2MiB>(addr, phys, max_size, flags) {
+            return result.map(|()| Size2MiB::SIZE);
+        }
+        self.try_map_page::<Size4KiB>(addr, phys, max_size, flags)
+            .unwrap_or(Err(MapToError::FrameAllocationFailed))
+            .map(|()| Size4KiB::SIZE)
+    }
+
+    // unmap all pages in the 'size' bytes starting at 'start', whatever their size
+    //
+    // the frames are given back to the frame allocator if 'free_frames' is set,
+    // unless they are still shared copy-on-write with other pages
+    pub fn unmap_range(&mut self, start: VirtAddr, size: u64, free_frames: bool) {
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            let walk = PageTableWalker::new(&mut self.mapper).walk(addr);
+            addr = match walk {
+                Ok(t) => {
+                    match t.page_size {
+                        MappedPageSize::Size4KiB => self.unmap_page::<Size4KiB>(t.page_start, free_frames),
+                        MappedPageSize::Size2MiB => self.unmap_page::<Size2MiB>(t.page_start, free_frames),
+                        MappedPageSize::Size1GiB => self.unmap_page::<Size1GiB>(t.page_start, free_frames),
+                    }
+                    t.page_start + t.page_size.bytes()
+                }
+                // skip the whole block that has no page table
+                Err(unmapped) => VirtAddr::new_truncate(addr.align_down(unmapped).as_u64() + unmapped),
+            };
+        }
+    }
+
+    // map a page of size S at 'addr' if it fits
+    //
+    // returns None if the page does not fit, no frame of size S is free,
+    // or a huge page cannot be mapped there
+    fn try_map_page<S: PageSize>(
+        &mut self,
+        addr: VirtAddr,
+        phys: Option<PhysAddr>,
+        max_size: u64,
+        flags: PageTableFlags,
+    ) -> Option<Result<(), MapToError<Size4KiB>>>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+        BuddyFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
+    {
+        if max_size < S::SIZE || !addr.is_aligned(S::SIZE) {
+            return None;
+        }
+        let page = Page::<S>::from_start_address(addr).ok()?;
+        let (frame, allocated) = match phys {
+            Some(phys) => (PhysFrame::<S>::from_start_address(phys).ok()?, false),
+            None => (FrameAllocator::<S>::allocate_frame(&mut self.frame_allocator)?, true),
+        };
+        let result = unsafe { self.mapper.map_to(page, frame, flags, &mut self.frame_allocator) };
+        Some(match result {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => {
+                if allocated {
+                    unsafe { self.frame_allocator.deallocate_frame(frame) };
+                }
+                // e.g. a page table left behind by small pages, fall back to those
+                if S::SIZE != Size4KiB::SIZE {
+                    return None;
+                }
+                Err(to_4kib_error(err))
+            }
+        })
+    }
+
+    fn unmap_page<S: PageSize>(&mut self, addr: VirtAddr, free_frame: bool)
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+        BuddyFrameAllocator: FrameDeallocator<S>,
     {
-        translate_addr_inner(addr, physical_memory_offset)
+        let page = Page::<S>::containing_address(addr);
+        if let Ok((frame, flush)) = self.mapper.unmap(page) {
+            flush.flush();
+            // only 4 KiB frames are ever shared
+            let shared = PhysFrame::containing_address(frame.start_address());
+            if free_frame && self.frame_refs.release(shared) {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+            }
+        }
     }
+}
 
-// private function that is called by 'translate_addr' to limit the scope of unsafe
+// size of the unmapped guard page below each kernel stack
+pub const STACK_GUARD_SIZE: u64 = Size4KiB::SIZE;
 
-// this function is safe to limit the scope of unsafe bc rust treats
-// the whole body of unsafe functions as unsafe. this function must only be reachable
-// through "unsafe fn" from outside this module
+// returns the region of the stack the bootloader started the kernel on
+//
+// the bootloader leaves the page below that stack unmapped, so the stack
+// is found by looking for unmapped pages below and above the current one
+fn boot_stack_region(mapper: &OffsetPageTable) -> Region {
+    use x86_64::structures::paging::Translate;
 
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
-    -> Option<PhysAddr>
-{
-    use x86_64::structures::paging::page_table::FrameError;
-    use x86_64::registers::control::Cr3;
+    // any local variable lives on the current stack
+    let marker = 0u8;
+    let current = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(&marker));
+    let mut bottom = current;
+    while mapper.translate_addr((bottom - 1).start_address()).is_some() {
+        bottom -= 1;
+    }
+    let mut top = current + 1;
+    while mapper.translate_addr(top.start_address()).is_some() {
+        top += 1;
+    }
+    let guard = bottom - 1;
+    Region {
+        start: guard.start_address(),
+        size: top.start_address() - guard.start_address(),
+        kind: RegionKind::Stack("boot"),
+        flags: PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        fault_handler: None,
+    }
+}
 
-    // read the active lvl 4 frame from the cr3 register
-    let (level_4_table_frame, _) = Cr3::read();
+// resolve a page fault at 'addr' if it is a write to a copy-on-write page,
+// otherwise pass it on to the fault handler of the region that contains it
+// returns whether the fault was resolved
+//
+// faults that happen while the kernel memory is locked are never resolved
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let Some(mut memory) = KERNEL_MEMORY.try_lock() else {
+        return false;
+    };
+    let Some(memory) = memory.as_mut() else {
+        return false;
+    };
+    if memory.resolve_copy_on_write(addr, error_code) {
+        return true;
+    }
+    let Some(region) = memory.regions.find(addr) else {
+        return false;
+    };
+    match region.fault_handler {
+        Some(handler) => handler(memory, &region, addr, error_code),
+        None => false,
+    }
+}
+
+// fault handler of demand-zero regions: back the faulting page with a zeroed frame
+fn demand_zero_fault(
+    memory: &mut KernelMemory,
+    region: &Region,
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> bool {
+    // the page is mapped, so this is a real access violation
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(&mut memory.frame_allocator) else {
+        return false;
+    };
+    let frame_ptr: *mut u8 = (memory.mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { frame_ptr.write_bytes(0, Size4KiB::SIZE as usize) };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match unsafe { memory.mapper.map_to(page, frame, region.flags, &mut memory.frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
 
-    let table_indexes = [
-        addr.p4_index(),
-        addr.p3_index(),
-        addr.p2_index(),
-        addr.p1_index(),
-    ];
-    let mut frame = level_4_table_frame;
-
-    // traverse the multi-level page table
-    for &index in &table_indexes {
-        // convert the frame into a page table refernce
-        let virt = physical_memory_offset + frame.start_address().as_u64();
-        let table_ptr: *const PageTable = virt.as_ptr();
-        let table = unsafe { &*table_ptr };
-
-        // read the page table entry at the given index
-        let entry = &table[index];
-        // update the frame
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+// returns the name of the kernel stack whose guard page contains 'addr'
+//
+// used by the fault handlers, so it gives up instead of waiting if the
+// kernel memory is locked
+pub fn stack_guard_hit(addr: VirtAddr) -> Option<&'static str> {
+    let memory = KERNEL_MEMORY.try_lock()?;
+    let region = memory.as_ref()?.regions.find(addr)?;
+    match region.kind {
+        RegionKind::Stack(name) if addr < region.start + STACK_GUARD_SIZE => Some(name),
+        _ => None,
+    }
+}
+
+// --- no-execute protection ---
+
+// ELF program header types and flags
+const PT_LOAD: u32 = 1;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// turn on the no-execute bit in the EFER register
+// without it, the NO_EXECUTE page table flag is a reserved bit
+fn enable_nxe() {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    unsafe { Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE) };
+}
+
+// make writes of the kernel to read-only pages fault, which copy-on-write relies on
+fn enable_write_protect() {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    unsafe { Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT) };
+}
+
+// remap the sections of the kernel image according to its ELF program headers:
+// .text read-only and executable, .rodata read-only, data writable but not executable
+//
+// the bootloader maps the ELF header as part of the first segment and the linker
+// provides its address as __ehdr_start
+fn protect_kernel_image(mapper: &mut OffsetPageTable) {
+    unsafe extern "C" {
+        static __ehdr_start: u8;
+    }
+
+    let ehdr = &raw const __ehdr_start;
+    assert_eq!(unsafe { ehdr.cast::<[u8; 4]>().read() }, *b"\x7fELF", "kernel ELF header not mapped");
+    let (phoff, phentsize, phnum) = unsafe {
+        (
+            ehdr.add(0x20).cast::<u64>().read_unaligned(),
+            ehdr.add(0x36).cast::<u16>().read_unaligned(),
+            ehdr.add(0x38).cast::<u16>().read_unaligned(),
+        )
+    };
+
+    for i in 0..u64::from(phnum) {
+        let phdr = unsafe { ehdr.add((phoff + i * u64::from(phentsize)) as usize) };
+        let (p_type, p_flags, p_vaddr, p_memsz) = unsafe {
+            (
+                phdr.cast::<u32>().read_unaligned(),
+                phdr.add(0x04).cast::<u32>().read_unaligned(),
+                phdr.add(0x10).cast::<u64>().read_unaligned(),
+                phdr.add(0x28).cast::<u64>().read_unaligned(),
+            )
         };
+        let flags = match p_type {
+            // W^X: executable segments are never writable
+            PT_LOAD if p_flags & PF_X != 0 => PageTableFlags::PRESENT,
+            PT_LOAD if p_flags & PF_W != 0 => {
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+            }
+            PT_LOAD => PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            // data that is only written while linking, read-only afterwards
+            PT_GNU_RELRO => PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            _ => continue,
+        };
+        let start = VirtAddr::new(p_vaddr);
+        set_page_flags(mapper, start, start + p_memsz, flags);
     }
+}
 
-    // calculate the physical address by adding the page offset
-    Some(frame.start_address() +u64::from(addr.page_offset()))
+// the bootloader maps its stack executable
+fn protect_boot_stack(mapper: &mut OffsetPageTable, boot_stack: &Region) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    set_page_flags(mapper, boot_stack.start + STACK_GUARD_SIZE, boot_stack.end(), flags);
 }
 
-*/
+// the mapping of the complete physical memory is only used to access data,
+// e.g. page tables, so the level 4 entry that holds it is made non-executable
+fn protect_physical_memory(mapper: &mut OffsetPageTable) {
+    let index = mapper.phys_offset().p4_index();
+    let entry = &mut mapper.level_4_table()[index];
+    entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+    x86_64::instructions::tlb::flush_all();
+}
 
-// use x86_64 offset page table implementation
-// already supports huge pages and more features
+// replace the WRITABLE and NO_EXECUTE flags of all 4 KiB pages in 'start..end'
+fn set_page_flags(mapper: &mut OffsetPageTable, start: VirtAddr, end: VirtAddr, flags: PageTableFlags) {
+    use x86_64::structures::paging::{mapper::TranslateResult, Translate};
 
+    let protection = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(start),
+        Page::containing_address(end - 1u64) + 1,
+    );
+    for page in pages {
+        if let TranslateResult::Mapped { flags: old_flags, .. } = mapper.translate(page.start_address()) {
+            let new_flags = (old_flags - protection) | flags;
+            if let Ok(flush) = unsafe { mapper.update_flags(page, new_flags) } {
+                flush.flush();
+            }
+        }
+    }
+}
 
-// init new offsetpagetable
+// returns whether the cpu supports 1 GiB pages
+fn supports_1gib_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
 
-// unsafe function bc caller must guarantee complete physical memory is mapped
-// only call once to avoid aliasing mutable references
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    unsafe {
-        let level_4_table = active_level_4_table(physical_memory_offset);
-        OffsetPageTable::new(level_4_table, physical_memory_offset)
+    // the pdpe1gb bit is in the extended feature flags
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+}
+
+// mapping errors are reported for 4 KiB pages, whatever page size was mapped
+fn to_4kib_error<S: PageSize>(err: MapToError<S>) -> MapToError<Size4KiB> {
+    match err {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
+
+static KERNEL_MEMORY: spin::Mutex<Option<KernelMemory>> = spin::Mutex::new(None);
+
+// hand the mapper and frame allocator over to the kernel
+// must be called before the heap is initialized
+// must be called on the stack the bootloader started the kernel on
+//
+// also enables no-execute protection and remaps the kernel image, so that
+// no page is both writable and executable
+pub fn init_kernel_memory(mut mapper: OffsetPageTable<'static>, mut frame_allocator: BuddyFrameAllocator) {
+    let boot_stack = boot_stack_region(&mapper);
+    enable_nxe();
+    enable_write_protect();
+    protect_kernel_image(&mut mapper);
+    protect_boot_stack(&mut mapper, &boot_stack);
+    protect_physical_memory(&mut mapper);
+
+    assert_user_space_unused(&mut mapper);
+
+    let mut regions = RegionAllocator::new();
+    regions
+        .insert(boot_stack)
+        .expect("boot stack overlaps kernel regions");
+    let frame_refs = FrameRefCounts::new(&mut frame_allocator, mapper.phys_offset());
+    *KERNEL_MEMORY.lock() = Some(KernelMemory {
+        mapper,
+        frame_allocator,
+        regions,
+        frame_refs,
+    });
+}
+
+// user space must not share any level 4 entry with the kernel, otherwise
+// the user memory accessors would accept kernel addresses
+fn assert_user_space_unused(mapper: &mut OffsetPageTable) {
+    use crate::cpu::{USER_SPACE_END, USER_SPACE_START};
+
+    let first = VirtAddr::new(USER_SPACE_START).p4_index();
+    let last = VirtAddr::new(USER_SPACE_END - 1).p4_index();
+    let table = mapper.level_4_table();
+    for index in u16::from(first)..=u16::from(last) {
+        assert!(table[usize::from(index)].is_unused(), "kernel memory mapped in user space");
+    }
+    // the heap and the kernel regions are placed below user space
+    const {
+        use crate::allocator::{HEAP_MAX_SIZE, HEAP_START};
+        assert!((HEAP_START + HEAP_MAX_SIZE) as u64 <= USER_SPACE_START);
+        assert!(region::KERNEL_VM_START + region::KERNEL_VM_SIZE <= USER_SPACE_START);
     }
 }
 
+// run the given closure with exclusive access to the kernel memory
+// returns None if init_kernel_memory was not called yet
+//
+// growing the heap needs the same lock, so allocations inside the closure
+// fail once the mapped heap is used up
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    KERNEL_MEMORY.lock().as_mut().map(f)
+}
+
+// like 'with_kernel_memory', but returns None instead of waiting if the
+// kernel memory is locked
+pub fn try_with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    KERNEL_MEMORY.try_lock()?.as_mut().map(f)
+}
+
 pub struct EmptyFrameAllocator;
 
 
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    // hands out frames in memory map order without ever re-walking the map,
+    // so each call takes constant time (amortized over the number of regions)
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        loop {
+            let region = self.memory_map.get(self.region)?;
+            if region.region_type == MemoryRegionType::Usable {
+                // skip forward to the start of the region
+                self.next = self.next.max(region.range.start_frame_number);
+                if self.next < region.range.end_frame_number {
+                    let frame_addr = PhysAddr::new(self.next * 4096);
+                    self.next += 1;
+                    return Some(PhysFrame::containing_address(frame_addr));
+                }
+            }
+            // region exhausted or not usable -> continue with the next region
+            self.region += 1;
+        }
     }
 }
 
@@ -122,7 +599,10 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 // frameallocator returns usable freames form the bootloaders memory map
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    // index of the memory region frames are currently taken from
+    region: usize,
+    // number of the next frame to hand out
+    next: u64,
 }
 
 impl BootInfoFrameAllocator {
@@ -132,20 +612,8 @@ impl BootInfoFrameAllocator {
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
         BootInfoFrameAllocator {
             memory_map,
+            region: 0,
             next: 0,
         }    
     }
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create physframe from start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-        }
-}
\ No newline at end of file
+}
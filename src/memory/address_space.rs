@@ -0,0 +1,248 @@
+// src/memory/address_space.rs
+
+use super::{
+    region::KERNEL_VM_START,
+    walk::{PageTableWalker, Translation},
+    KernelMemory,
+};
+use crate::allocator::HEAP_START;
+use crate::cpu::{USER_SPACE_END, USER_SPACE_START};
+use crate::serial_println;
+use core::mem::ManuallyDrop;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+// error returned when mapping a page into an address space fails
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    // the page belongs to the kernel, which is shared by all address spaces
+    KernelAddress,
+    // mapping the page failed
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for AddressSpaceError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        AddressSpaceError::Map(err)
+    }
+}
+
+// a set of page tables with the kernel mapped into it
+//
+// the kernel is not linked into the upper half, so the kernel part is made up
+// of all level 4 entries that the kernel uses when the address space is
+// created. These entries point to the kernel's own level 3 tables and are
+// shared with every address space. User pages can only be mapped into the
+// user window 'USER_SPACE_START..USER_SPACE_END', which the kernel never uses
+//
+// dropping the address space frees all of its page tables, but not the frames
+// that are mapped by them. It locks the kernel memory to do so and panics if
+// that is locked already, so inside of 'with_kernel_memory' the address space
+// must be given to 'destroy' instead
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    phys_offset: VirtAddr,
+}
+
+impl AddressSpace {
+    // creates an address space that only contains the kernel
+    pub fn new(memory: &mut KernelMemory) -> Result<Self, AddressSpaceError> {
+        // entries the kernel may only fill later, these must be shared from the start
+        for addr in [KERNEL_VM_START, HEAP_START as u64] {
+            create_kernel_entry(memory, VirtAddr::new(addr))?;
+        }
+
+        let level_4_frame = FrameAllocator::<Size4KiB>::allocate_frame(&mut memory.frame_allocator)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let phys_offset = memory.mapper.phys_offset();
+        let mut space = AddressSpace { level_4_frame, phys_offset };
+
+        let kernel_table = memory.mapper.level_4_table();
+        let table = space.level_4_table();
+        table.zero();
+        for (entry, kernel_entry) in table.iter_mut().zip(kernel_table.iter()) {
+            if !kernel_entry.is_unused() {
+                entry.set_addr(kernel_entry.addr(), kernel_entry.flags());
+            }
+        }
+        Ok(space)
+    }
+
+    // returns the frame of the level 4 table, which is loaded into CR3
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    // maps 'page' to 'frame' as a user page
+    //
+    // 'USER_ACCESSIBLE' is added to 'flags' and to all page tables on the way.
+    // New page tables are taken from the frame allocator of 'memory'
+    pub fn map_user_page(
+        &mut self,
+        memory: &mut KernelMemory,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        if !self.is_user_page(memory, page) {
+            return Err(AddressSpaceError::KernelAddress);
+        }
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = self.mapper();
+        let allocator = &mut memory.frame_allocator;
+        let flush = unsafe { mapper.map_to_with_table_flags(page, frame, flags, table_flags, allocator)? };
+        // the tlb only caches the active address space
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(())
+    }
+
+    // unmaps the user page 'page' and returns the frame it was mapped to
+    //
+    // the frame is not freed. Page tables that become empty are kept until the
+    // address space is dropped
+    pub fn unmap_user_page(
+        &mut self,
+        memory: &mut KernelMemory,
+        page: Page,
+    ) -> Result<PhysFrame, UnmapError> {
+        if !self.is_user_page(memory, page) {
+            return Err(UnmapError::PageNotMapped);
+        }
+        let active = self.is_active();
+        let (frame, flush) = self.mapper().unmap(page)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(frame)
+    }
+
+    // translates 'addr' in this address space, see 'PageTableWalker::translate'
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<Translation> {
+        PageTableWalker::new(&mut self.mapper()).translate(addr)
+    }
+
+    // returns whether the address space is loaded into CR3
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    // loads the address space into CR3
+    //
+    // the kernel stays mapped, but user pages of the previously active
+    // address space are no longer accessible
+    pub fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(self.level_4_frame, flags) };
+    }
+
+    // frees the page tables like dropping the address space, with the kernel
+    // memory that is already locked by the caller
+    pub fn destroy(self, memory: &mut KernelMemory) {
+        ManuallyDrop::new(self).free_tables(memory);
+    }
+
+    fn free_tables(&mut self, memory: &mut KernelMemory) {
+        let phys_offset = self.phys_offset;
+        if self.is_active() {
+            activate_kernel(memory);
+        }
+        for index in 0..512 {
+            let entry = &self.level_4_table()[index];
+            // shared entries belong to the kernel
+            let kernel_entry = &memory.mapper.level_4_table()[index];
+            if entry.is_unused() || entry.addr() == kernel_entry.addr() {
+                continue;
+            }
+            let frame = PhysFrame::containing_address(entry.addr());
+            unsafe { free_table(memory, phys_offset, frame, 3) };
+        }
+        unsafe { memory.frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+
+    fn is_user_page(&self, memory: &mut KernelMemory, page: Page) -> bool {
+        let addr = page.start_address();
+        (USER_SPACE_START..USER_SPACE_END).contains(&addr.as_u64())
+            && memory.mapper.level_4_table()[addr.p4_index()].is_unused()
+    }
+
+    fn level_4_table(&mut self) -> &mut PageTable {
+        let virt = self.phys_offset + self.level_4_frame.start_address().as_u64();
+        unsafe { &mut *virt.as_mut_ptr() }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let phys_offset = self.phys_offset;
+        unsafe { OffsetPageTable::new(self.level_4_table(), phys_offset) }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // the lock is not reentrant, so waiting for it could hang forever
+        let Some(mut memory) = super::KERNEL_MEMORY.try_lock() else {
+            panic!("address space dropped with the kernel memory locked, use destroy instead");
+        };
+        match memory.as_mut() {
+            Some(memory) => self.free_tables(memory),
+            None => {
+                serial_println!("address space {:?} leaked: kernel memory not initialized", self.level_4_frame);
+            }
+        }
+    }
+}
+
+// loads the kernel's own page tables into CR3
+pub fn activate_kernel(memory: &mut KernelMemory) {
+    let virt = VirtAddr::from_ptr(memory.mapper.level_4_table() as *const PageTable);
+    let phys = PhysAddr::new(virt - memory.mapper.phys_offset());
+    let (_, flags) = Cr3::read();
+    unsafe { Cr3::write(PhysFrame::containing_address(phys), flags) };
+}
+
+// creates an empty level 3 table for 'addr' in the kernel's level 4 table,
+// if there is none yet
+fn create_kernel_entry(memory: &mut KernelMemory, addr: VirtAddr) -> Result<(), AddressSpaceError> {
+    let phys_offset = memory.mapper.phys_offset();
+    if !memory.mapper.level_4_table()[addr.p4_index()].is_unused() {
+        return Ok(());
+    }
+    let frame = FrameAllocator::<Size4KiB>::allocate_frame(&mut memory.frame_allocator)
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let table: *mut PageTable = (phys_offset + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { (*table).zero() };
+    memory.mapper.level_4_table()[addr.p4_index()]
+        .set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    Ok(())
+}
+
+// frees the page table in 'frame' at 'level' and all tables below it
+//
+// the table must not be used anymore
+unsafe fn free_table(memory: &mut KernelMemory, phys_offset: VirtAddr, frame: PhysFrame, level: u8) {
+    let table: &PageTable = unsafe { &*(phys_offset + frame.start_address().as_u64()).as_ptr() };
+    if level > 1 {
+        for entry in table.iter() {
+            if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                continue;
+            }
+            let child = PhysFrame::containing_address(entry.addr());
+            unsafe { free_table(memory, phys_offset, child, level - 1) };
+        }
+    }
+    unsafe { memory.frame_allocator.deallocate_frame(frame) };
+}
@@ -0,0 +1,147 @@
+// src/memory/bitmap.rs
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+    VirtAddr,
+};
+
+// size of a physical frame in bytes
+const FRAME_SIZE: u64 = 4096;
+
+// number of frames tracked by one word of the bitmap
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+// a physical frame allocator that tracks every frame with a single bit
+//
+// a set bit marks a frame as used, a cleared bit marks it as free. Frames that
+// are not usable according to the memory map are marked as used forever. The
+// bitmap itself is stored in the first usable region that is large enough to
+// hold it and is accessed through the complete physical memory mapping
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    total_frames: usize,
+    free_frames: usize,
+    // all frames below this index are known to be used
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    // creates a bitmap frame allocator from the passed memory map
+    //
+    // unsafe because the caller must guarantee that the memory map is valid,
+    // that all frames marked as 'Usable' are really unused and that the complete
+    // physical memory is mapped to virtual memory at the passed offset. This
+    // function must only be called once
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // the bitmap must cover every frame up to the end of the last usable region
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_frames = ((words * 8) as u64).div_ceil(FRAME_SIZE);
+
+        // place the bitmap at the start of the first region that can hold it
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .expect("no usable region large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_frame_number;
+        let bitmap_ptr: *mut u64 =
+            (physical_memory_offset + bitmap_start * FRAME_SIZE).as_mut_ptr();
+        let bitmap = unsafe { slice::from_raw_parts_mut(bitmap_ptr, words) };
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            total_frames: 0,
+            free_frames: 0,
+            next: 0,
+        };
+        // mark all usable frames as free
+        for region in usable_regions() {
+            for index in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.clear(index as usize);
+                allocator.total_frames += 1;
+                allocator.free_frames += 1;
+            }
+        }
+        // reserve the frames that hold the bitmap itself
+        for index in bitmap_start..bitmap_start + bitmap_frames {
+            allocator.set(index as usize);
+            allocator.free_frames -= 1;
+        }
+        allocator
+    }
+
+    // returns the number of usable frames managed by this allocator
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    // returns the number of frames that are currently free
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // returns the number of frames that are currently in use, including the
+    // frames that hold the bitmap
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    // finds the index of the first free frame at or after 'self.next'
+    fn find_free(&self) -> Option<usize> {
+        let first_word = self.next / BITS_PER_WORD;
+        self.bitmap
+            .iter()
+            .enumerate()
+            .skip(first_word)
+            .find(|(_, word)| **word != u64::MAX)
+            .map(|(i, word)| i * BITS_PER_WORD + (!word).trailing_zeros() as usize)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let index = self.find_free()?;
+        self.set(index);
+        self.free_frames -= 1;
+        self.next = index + 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(
+            index / BITS_PER_WORD < self.bitmap.len() && self.is_set(index),
+            "deallocating frame {:?} that is not allocated",
+            frame
+        );
+        self.clear(index);
+        self.free_frames += 1;
+        self.next = self.next.min(index);
+    }
+}
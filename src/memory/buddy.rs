@@ -0,0 +1,274 @@
+// src/memory/buddy.rs
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr,
+    VirtAddr,
+};
+
+// size of a physical frame in bytes
+const FRAME_SIZE: u64 = 4096;
+
+// the largest block order. A block of order 'n' spans 2^n frames, so the
+// largest block covers 1 GiB
+pub const MAX_ORDER: usize = 18;
+
+// the order of a block backing a single 2 MiB frame
+pub const ORDER_2MIB: usize = 9;
+
+// the order of a block backing a single 1 GiB frame
+pub const ORDER_1GIB: usize = 18;
+
+// node for a doubly linked list of free blocks
+//
+// the node is written into the first frame of the free block it describes. The
+// size of the block is determined by the free list the node is stored in
+struct FreeBlock {
+    prev: Option<PhysAddr>,
+    next: Option<PhysAddr>,
+}
+
+// a buddy system physical frame allocator
+//
+// serves blocks of 2^order physically contiguous frames that are aligned to
+// their own size. Freed blocks are merged with their buddy whenever the buddy
+// is free as well, so large blocks become available again after small
+// allocations are returned
+//
+// the order of every free block is also recorded in a table with one byte per
+// frame, so whether a buddy is free is known without walking a free list
+pub struct BuddyFrameAllocator {
+    free_lists: [Option<PhysAddr>; MAX_ORDER + 1],
+    // order + 1 for the first frame of each free block, 0 for all other frames
+    free_orders: &'static mut [u8],
+    physical_memory_offset: VirtAddr,
+    total_frames: usize,
+    free_frames: usize,
+    frame_limit: u64,
+}
+
+impl BuddyFrameAllocator {
+    // creates a buddy frame allocator from the passed memory map
+    //
+    // unsafe because the caller must guarantee that the memory map is valid,
+    // that all frames marked as 'Usable' are really unused and that the complete
+    // physical memory is mapped to virtual memory at the passed offset. This
+    // function must only be called once
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // the order table covers every frame up to the end of the last usable region
+        let frame_limit = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0);
+        let table_frames = frame_limit.div_ceil(FRAME_SIZE);
+        // place the table at the start of the first region that can hold it
+        let table_start = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= table_frames)
+            .expect("no usable region large enough for the buddy order table")
+            .range
+            .start_frame_number;
+        let table_ptr: *mut u8 = (physical_memory_offset + table_start * FRAME_SIZE).as_mut_ptr();
+        let free_orders = unsafe { slice::from_raw_parts_mut(table_ptr, frame_limit as usize) };
+        free_orders.fill(0);
+
+        let mut allocator = BuddyFrameAllocator {
+            free_lists: [None; MAX_ORDER + 1],
+            free_orders,
+            physical_memory_offset,
+            total_frames: 0,
+            free_frames: 0,
+            frame_limit,
+        };
+        for region in usable_regions() {
+            let frames = (region.range.end_frame_number - region.range.start_frame_number) as usize;
+            allocator.total_frames += frames;
+            // the frames of the table are used from the start
+            let mut start = region.range.start_frame_number;
+            if start == table_start {
+                start += table_frames;
+            }
+            let end = region.range.end_frame_number;
+            allocator.free_frames += end.saturating_sub(start) as usize;
+
+            // split the region into the largest naturally aligned blocks
+            while start < end {
+                let mut order = (start.trailing_zeros() as usize).min(MAX_ORDER);
+                while start + (1 << order) > end {
+                    order -= 1;
+                }
+                unsafe {
+                    allocator.push(order, PhysAddr::new(start * FRAME_SIZE));
+                }
+                start += 1 << order;
+            }
+        }
+        allocator
+    }
+
+    // returns the number of usable frames managed by this allocator
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    // returns the number of frames that are currently free
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // returns the number of the first frame above all usable frames
+    pub fn frame_limit(&self) -> u64 {
+        self.frame_limit
+    }
+
+    // returns the number of frames that are currently in use, including the
+    // frames that hold the order table
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    // allocates 2^order physically contiguous frames aligned to their size
+    //
+    // returns the first frame of the block or None if no block is free
+    pub fn allocate_order(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        // find the smallest free block that is large enough
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.free_lists[current].unwrap();
+        self.unlink(current, addr);
+        // split the block until it has the requested order, freeing the upper halves
+        while current > order {
+            current -= 1;
+            unsafe {
+                self.push(current, addr + block_size(current));
+            }
+        }
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(addr))
+    }
+
+    // frees a block of 2^order frames starting at 'frame'
+    //
+    // unsafe because the caller must guarantee that the block was allocated from
+    // this allocator with the same or a larger order and that it is no longer used
+    pub unsafe fn deallocate_order(&mut self, frame: PhysFrame, order: usize) {
+        let mut addr = frame.start_address();
+        assert!(order <= MAX_ORDER, "invalid block order {}", order);
+        assert!(addr.is_aligned(block_size(order)), "block {:?} is not aligned to order {}", frame, order);
+        self.free_frames += 1 << order;
+
+        // merge with the buddy as long as the buddy is free as well
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = PhysAddr::new(addr.as_u64() ^ block_size(order));
+            if !self.is_free(order, buddy) {
+                break;
+            }
+            self.unlink(order, buddy);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        unsafe {
+            self.push(order, addr);
+        }
+    }
+
+    // returns a pointer to the free list node stored in the block at 'addr'
+    fn node_ptr(&self, addr: PhysAddr) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr.as_u64()).as_mut_ptr()
+    }
+
+    // returns the index of the frame at 'addr' in the order table
+    fn frame_index(addr: PhysAddr) -> usize {
+        (addr.as_u64() / FRAME_SIZE) as usize
+    }
+
+    // returns whether the block at 'addr' is a free block of 'order'
+    fn is_free(&self, order: usize, addr: PhysAddr) -> bool {
+        self.free_orders.get(Self::frame_index(addr)) == Some(&(order as u8 + 1))
+    }
+
+    // adds the block at 'addr' to the front of the free list for 'order'
+    //
+    // the block must be free and must not be in any free list yet
+    unsafe fn push(&mut self, order: usize, addr: PhysAddr) {
+        let next = self.free_lists[order];
+        if let Some(next) = next {
+            unsafe { (*self.node_ptr(next)).prev = Some(addr) };
+        }
+        unsafe { self.node_ptr(addr).write(FreeBlock { prev: None, next }) };
+        self.free_lists[order] = Some(addr);
+        self.free_orders[Self::frame_index(addr)] = order as u8 + 1;
+    }
+
+    // removes the free block at 'addr' from the free list for 'order'
+    //
+    // the block must be in that list, see 'is_free'
+    fn unlink(&mut self, order: usize, addr: PhysAddr) {
+        let FreeBlock { prev, next } = unsafe { self.node_ptr(addr).read() };
+        match prev {
+            Some(prev) => unsafe { (*self.node_ptr(prev)).next = next },
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            unsafe { (*self.node_ptr(next)).prev = prev };
+        }
+        self.free_orders[Self::frame_index(addr)] = 0;
+    }
+}
+
+// returns the size of a block of the given order in bytes
+fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_order(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe { self.deallocate_order(frame, 0) }
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_order(ORDER_2MIB)?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        unsafe { self.deallocate_order(frame, ORDER_2MIB) }
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        let frame = self.allocate_order(ORDER_1GIB)?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        unsafe { self.deallocate_order(frame, ORDER_1GIB) }
+    }
+}
@@ -0,0 +1,246 @@
+// src/memory/cow.rs
+
+use super::{
+    buddy::BuddyFrameAllocator,
+    region::{Region, RegionError},
+    KernelMemory,
+};
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MappedFrame, TranslateResult},
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+            Size4KiB, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+// marks a page whose frame is shared copy-on-write
+//
+// the page is mapped read-only and gets its own copy of the frame on the
+// first write. Uses one of the page table entry bits that are available to
+// the operating system
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+// number of mappings of each physical frame that is shared
+//
+// frames that are not shared have a count of 0, so that no counter needs to be
+// touched when frames are mapped normally
+pub struct FrameRefCounts {
+    counts: &'static mut [u16],
+}
+
+impl FrameRefCounts {
+    // allocates a counter for every frame below 'frame_allocator.frame_limit()'
+    //
+    // the counters are stored in physically contiguous frames taken from the
+    // frame allocator. Panics if there are not enough of them
+    pub fn new(frame_allocator: &mut BuddyFrameAllocator, physical_memory_offset: VirtAddr) -> Self {
+        let len = frame_allocator.frame_limit() as usize;
+        let frames = (len * 2).div_ceil(Size4KiB::SIZE as usize).max(1);
+        let order = frames.next_power_of_two().trailing_zeros() as usize;
+        let start = frame_allocator
+            .allocate_order(order)
+            .expect("no frames for the frame reference counts");
+
+        let ptr: *mut u16 = (physical_memory_offset + start.start_address().as_u64()).as_mut_ptr();
+        let counts = unsafe {
+            ptr.write_bytes(0, len);
+            core::slice::from_raw_parts_mut(ptr, len)
+        };
+        FrameRefCounts { counts }
+    }
+
+    // returns the number of mappings of 'frame', or 0 if it is not shared
+    pub fn get(&self, frame: PhysFrame) -> u16 {
+        self.counts.get(Self::index(frame)).copied().unwrap_or(0)
+    }
+
+    // records one more mapping of 'frame', which is mapped at least once already
+    //
+    // returns false if the frame cannot be counted, e.g. because it is device memory
+    fn share(&mut self, frame: PhysFrame) -> bool {
+        match self.counts.get_mut(Self::index(frame)) {
+            Some(count) if *count < u16::MAX => {
+                *count = (*count).max(1) + 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // removes one mapping of 'frame'
+    //
+    // returns whether it was the last one, in which case the frame can be freed
+    pub fn release(&mut self, frame: PhysFrame) -> bool {
+        match self.counts.get_mut(Self::index(frame)) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(count) => {
+                *count = 0;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / Size4KiB::SIZE) as usize
+    }
+}
+
+impl KernelMemory {
+    // maps the 'size' bytes at 'dst' to the same frames as the ones at 'src'
+    //
+    // writable pages become read-only and copy-on-write in both ranges.
+    // Unmapped pages of 'src' are skipped. Huge pages and device memory
+    // cannot be shared
+    pub fn share_copy_on_write(
+        &mut self,
+        src: VirtAddr,
+        dst: VirtAddr,
+        size: u64,
+    ) -> Result<(), RegionError> {
+        for offset in (0..size).step_by(Size4KiB::SIZE as usize) {
+            let (frame, flags) = match self.mapper.translate(src + offset) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+                TranslateResult::Mapped { .. } => return Err(RegionError::CannotShare),
+                _ => continue,
+            };
+            if !self.frame_refs.share(frame) {
+                return Err(RegionError::CannotShare);
+            }
+
+            let shared_flags = if flags.contains(PageTableFlags::WRITABLE) {
+                (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+            } else {
+                flags
+            };
+            let src_page = Page::<Size4KiB>::containing_address(src + offset);
+            let dst_page = Page::<Size4KiB>::containing_address(dst + offset);
+            let result = unsafe {
+                match self.mapper.update_flags(src_page, shared_flags) {
+                    Ok(flush) => {
+                        flush.flush();
+                        self.mapper
+                            .map_to(dst_page, frame, shared_flags, &mut self.frame_allocator)
+                            .map(|flush| flush.flush())
+                            .map_err(|err| {
+                                // give the source page its flags back
+                                if let Ok(flush) = self.mapper.update_flags(src_page, flags) {
+                                    flush.flush();
+                                }
+                                RegionError::Map(err)
+                            })
+                    }
+                    Err(_) => Err(RegionError::CannotShare),
+                }
+            };
+            if let Err(err) = result {
+                // the frame is not shared after all
+                self.frame_refs.release(frame);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // creates a copy of the region that starts at 'start', sharing all of its
+    // frames copy-on-write
+    //
+    // the copy gets the same kind, flags and fault handler
+    pub fn clone_region_copy_on_write(&mut self, start: VirtAddr) -> Result<Region, RegionError> {
+        let region = self
+            .regions
+            .find(start)
+            .filter(|r| r.start == start)
+            .ok_or(RegionError::NotFound)?;
+        let copy = self.regions.allocate(region.size, region.kind, region.flags)?;
+        let shared = match region.fault_handler {
+            Some(handler) => self.regions.set_fault_handler(copy.start, handler),
+            None => Ok(()),
+        }
+        .and_then(|()| self.share_copy_on_write(region.start, copy.start, region.size));
+        if let Err(err) = shared {
+            // the sharing error is the one the caller needs to see
+            let _ = self.unmap_region(copy.start);
+            return Err(err);
+        }
+        Ok(Region { fault_handler: region.fault_handler, ..copy })
+    }
+
+    // resolves a write to a copy-on-write page at 'addr'
+    //
+    // copies the frame if it is still shared, otherwise makes the page writable
+    // again. Returns whether the fault was caused by copy-on-write
+    pub(super) fn resolve_copy_on_write(
+        &mut self,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> bool {
+        // a write to a page that is present
+        let write = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+        if !error_code.contains(write) {
+            return false;
+        }
+        let (frame, flags) = match self.mapper.translate(addr) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+            _ => return false,
+        };
+        if !flags.contains(COPY_ON_WRITE) {
+            return false;
+        }
+
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let private_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        let count = self.frame_refs.get(frame);
+        if count <= 1 {
+            // the other mappings are gone, keep the frame and reset its count,
+            // a count of 0 means the frame was never shared
+            if count == 1 {
+                self.frame_refs.release(frame);
+            }
+            return match unsafe { self.mapper.update_flags(page, private_flags) } {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(_) => false,
+            };
+        }
+
+        let Some(copy) = FrameAllocator::<Size4KiB>::allocate_frame(&mut self.frame_allocator) else {
+            return false;
+        };
+        let offset = self.mapper.phys_offset();
+        unsafe {
+            let src: *const u8 = (offset + frame.start_address().as_u64()).as_ptr();
+            let dst: *mut u8 = (offset + copy.start_address().as_u64()).as_mut_ptr();
+            core::ptr::copy_nonoverlapping(src, dst, Size4KiB::SIZE as usize);
+        }
+        let Ok((_, flush)) = self.mapper.unmap(page) else {
+            unsafe { self.frame_allocator.deallocate_frame(copy) };
+            return false;
+        };
+        flush.flush();
+        match unsafe { self.mapper.map_to(page, copy, private_flags, &mut self.frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                self.frame_refs.release(frame);
+                true
+            }
+            Err(_) => {
+                // map the shared frame again, its page table still exists
+                if let Ok(flush) = unsafe { self.mapper.map_to(page, frame, flags, &mut self.frame_allocator) } {
+                    flush.flush();
+                }
+                unsafe { self.frame_allocator.deallocate_frame(copy) };
+                false
+            }
+        }
+    }
+}
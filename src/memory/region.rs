@@ -0,0 +1,222 @@
+// src/memory/region.rs
+
+use super::KernelMemory;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{mapper::MapToError, page::PageRange, Page, PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB},
+    VirtAddr,
+};
+
+// start of the kernel address space that regions are allocated from
+pub const KERNEL_VM_START: u64 = 0x_5555_0000_0000;
+
+// size of the kernel address space that regions are allocated from
+pub const KERNEL_VM_SIZE: u64 = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+// maximum number of regions that can exist at the same time
+//
+// the regions are stored in a fixed table so that the heap itself can be a region
+pub const MAX_REGIONS: usize = 64;
+
+// what a region of the kernel address space is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    // the kernel heap, which maps its pages itself as it grows
+    Heap,
+    // a kernel stack with the given name. Its lowest page is an unmapped guard page
+    Stack(&'static str),
+    // device memory. Its frames belong to the device and are never freed
+    Mmio,
+    // a buffer, e.g. for a driver
+    Buffer,
+}
+
+// handles a page fault inside a region
+//
+// gets the kernel memory, the region, the faulting address and the error code.
+// Returns whether the fault was resolved, in which case the faulting
+// instruction is run again. Runs in the page fault handler with the kernel
+// memory locked, so it must not allocate on the heap
+pub type FaultHandler = fn(&mut KernelMemory, &Region, VirtAddr, PageFaultErrorCode) -> bool;
+
+// a range of the kernel address space
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    // first address of the region, page aligned
+    pub start: VirtAddr,
+    // size of the region in bytes, a multiple of the page size
+    pub size: u64,
+    // what the region is used for
+    pub kind: RegionKind,
+    // flags the pages of the region are mapped with
+    pub flags: PageTableFlags,
+    // called for page faults inside the region, if set
+    pub fault_handler: Option<FaultHandler>,
+}
+
+impl Region {
+    // returns the first address after the region
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    // returns whether 'addr' lies inside the region
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    // returns the pages of the region
+    pub fn pages(&self) -> PageRange {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end()),
+        )
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end()
+    }
+}
+
+// error returned by region operations
+#[derive(Debug)]
+pub enum RegionError {
+    // there is no free range of the requested size left
+    OutOfAddressSpace,
+    // the region table is full
+    TooManyRegions,
+    // the range overlaps an existing region
+    Overlap,
+    // there is no region at the given address
+    NotFound,
+    // the pages cannot be shared copy-on-write, e.g. because they are huge pages
+    CannotShare,
+    // mapping a page of the region failed
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for RegionError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        RegionError::Map(err)
+    }
+}
+
+// hands out non-overlapping ranges of the kernel address space and
+// remembers what each of them is used for
+//
+// only the bookkeeping happens here; 'KernelMemory' maps and unmaps the pages
+pub struct RegionAllocator {
+    regions: [Option<Region>; MAX_REGIONS],
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl Default for RegionAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegionAllocator {
+    // creates an allocator for the range 'KERNEL_VM_START..KERNEL_VM_START + KERNEL_VM_SIZE'
+    pub fn new() -> Self {
+        let start = VirtAddr::new(KERNEL_VM_START);
+        RegionAllocator {
+            regions: [None; MAX_REGIONS],
+            start,
+            end: start + KERNEL_VM_SIZE,
+        }
+    }
+
+    // reserves a free range of at least 'size' bytes
+    //
+    // the size is rounded up to whole pages. Regions of at least 2 MiB or 1 GiB
+    // are aligned to that size. Nothing is mapped
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        kind: RegionKind,
+        flags: PageTableFlags,
+    ) -> Result<Region, RegionError> {
+        if size > KERNEL_VM_SIZE {
+            return Err(RegionError::OutOfAddressSpace);
+        }
+        let size = align_up(size.max(1), Size4KiB::SIZE);
+        // align large regions so that they can be mapped with huge pages
+        let align = [Size1GiB::SIZE, Size2MiB::SIZE, Size4KiB::SIZE]
+            .into_iter()
+            .find(|&page_size| size >= page_size)
+            .unwrap();
+        // first fit: skip past every region in the way until the range is free
+        let mut start = self.start.align_up(align);
+        loop {
+            let end = start
+                .as_u64()
+                .checked_add(size)
+                .filter(|&end| end <= self.end.as_u64())
+                .ok_or(RegionError::OutOfAddressSpace)?;
+            match self.iter().find(|r| r.overlaps(start, VirtAddr::new(end))) {
+                Some(region) => start = region.end().align_up(align),
+                None => break,
+            }
+        }
+        let region = Region { start, size, kind, flags, fault_handler: None };
+        self.insert(region)?;
+        Ok(region)
+    }
+
+    // records a region at a fixed address, e.g. the heap
+    //
+    // the region may lie outside of the range regions are allocated from
+    pub fn insert(&mut self, region: Region) -> Result<(), RegionError> {
+        if self.iter().any(|r| r.overlaps(region.start, region.end())) {
+            return Err(RegionError::Overlap);
+        }
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegionError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(())
+    }
+
+    // forgets the region that starts at 'start' and returns it
+    pub fn remove(&mut self, start: VirtAddr) -> Result<Region, RegionError> {
+        self.regions
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|r| r.start == start))
+            .and_then(Option::take)
+            .ok_or(RegionError::NotFound)
+    }
+
+    // sets the handler for page faults inside the region that starts at 'start'
+    pub fn set_fault_handler(
+        &mut self,
+        start: VirtAddr,
+        handler: FaultHandler,
+    ) -> Result<(), RegionError> {
+        let region = self
+            .regions
+            .iter_mut()
+            .flatten()
+            .find(|r| r.start == start)
+            .ok_or(RegionError::NotFound)?;
+        region.fault_handler = Some(handler);
+        Ok(())
+    }
+
+    // returns the region that contains 'addr'
+    pub fn find(&self, addr: VirtAddr) -> Option<Region> {
+        self.iter().find(|r| r.contains(addr))
+    }
+
+    // returns an iterator over all regions, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = Region> + '_ {
+        self.regions.iter().flatten().copied()
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
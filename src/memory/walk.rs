@@ -0,0 +1,190 @@
+// src/memory/walk.rs
+
+use crate::serial_println;
+use x86_64::{
+    structures::paging::{OffsetPageTable, PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+// size of the page that maps an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedPageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappedPageSize {
+    // returns the size of the page in bytes
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappedPageSize::Size4KiB => 4096,
+            MappedPageSize::Size2MiB => 2 * 1024 * 1024,
+            MappedPageSize::Size1GiB => 1024 * 1024 * 1024,
+        }
+    }
+}
+
+// result of translating a virtual address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    // physical address the virtual address maps to
+    pub phys_addr: PhysAddr,
+    // virtual address of the page that contains the address
+    pub page_start: VirtAddr,
+    // size of that page
+    pub page_size: MappedPageSize,
+    // flags that are in effect for the page
+    //
+    // 'WRITABLE' and 'USER_ACCESSIBLE' are only set if every level allows it,
+    // 'NO_EXECUTE' is set if any level forbids execution. All other flags are
+    // the ones of the last entry
+    pub flags: PageTableFlags,
+}
+
+// reads the page tables of an 'OffsetPageTable' without changing them
+//
+// unlike 'Translate' from the 'x86_64' crate, the walker reports the flags of
+// all levels combined and can dump the whole hierarchy
+pub struct PageTableWalker<'a> {
+    level_4_table: &'a PageTable,
+    phys_offset: VirtAddr,
+}
+
+impl<'a> PageTableWalker<'a> {
+    // creates a walker for the page tables of 'mapper'
+    //
+    // borrows the mapper mutably so that the tables cannot change during the walk
+    pub fn new(mapper: &'a mut OffsetPageTable) -> Self {
+        let phys_offset = mapper.phys_offset();
+        PageTableWalker {
+            level_4_table: mapper.level_4_table(),
+            phys_offset,
+        }
+    }
+
+    // translates 'addr', returns None if it is not mapped
+    pub fn translate(&self, addr: VirtAddr) -> Option<Translation> {
+        self.walk(addr).ok()
+    }
+
+    // translates 'addr' like 'translate'
+    //
+    // if 'addr' is not mapped, returns the size of the aligned block around it
+    // that is not mapped either, because a whole table is missing. Useful to
+    // skip over large unmapped ranges
+    pub fn walk(&self, addr: VirtAddr) -> Result<Translation, u64> {
+        let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+        let mut table = self.level_4_table;
+        let mut flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        for (level, &index) in indexes.iter().enumerate() {
+            let entry = &table[index];
+            let entry_flags = entry.flags();
+            if !entry_flags.contains(PageTableFlags::PRESENT) {
+                // a level 4 entry covers 512 GiB, each level below 512 times less
+                return Err(1 << (39 - 9 * level));
+            }
+            flags = combine(flags, entry_flags);
+
+            let page_size = match level {
+                3 => Some(MappedPageSize::Size4KiB),
+                2 if entry_flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappedPageSize::Size2MiB),
+                1 if entry_flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappedPageSize::Size1GiB),
+                _ => None,
+            };
+            if let Some(page_size) = page_size {
+                let offset = addr.as_u64() & (page_size.bytes() - 1);
+                return Ok(Translation {
+                    phys_addr: entry.addr() + offset,
+                    page_start: addr - offset,
+                    page_size,
+                    flags,
+                });
+            }
+            table = self.table_at(entry.addr());
+        }
+        unreachable!("the level 1 entry always maps a page")
+    }
+
+    // prints all present entries of the page table hierarchy over serial
+    //
+    // tables are printed with their index and flags. Consecutive pages that map
+    // consecutive frames with the same flags are printed as one range
+    pub fn dump(&self) {
+        serial_println!("--- page tables ---");
+        let mut run = None;
+        self.dump_table(self.level_4_table, 4, 0, &mut run);
+        print_run(&mut run);
+    }
+
+    fn dump_table(&self, table: &PageTable, level: u8, base: u64, run: &mut Option<Run>) {
+        for (index, entry) in table.iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let shift = 12 + 9 * (u64::from(level) - 1);
+            let virt = base | (index as u64) << shift;
+
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                let start = VirtAddr::new_truncate(virt);
+                let size = 1 << shift;
+                match run {
+                    Some(r) if r.extends(start, entry.addr(), flags) => r.size += size,
+                    _ => {
+                        print_run(run);
+                        *run = Some(Run { start, phys: entry.addr(), size, flags });
+                    }
+                }
+            } else {
+                print_run(run);
+                let indent = (4 - level as usize) * 2;
+                serial_println!(
+                    "{:indent$}P{}[{:3}] {:#x} {:?}",
+                    "", level, index, entry.addr().as_u64(), flags,
+                    indent = indent
+                );
+                self.dump_table(self.table_at(entry.addr()), level - 1, virt, run);
+            }
+        }
+    }
+
+    fn table_at(&self, phys: PhysAddr) -> &'a PageTable {
+        let virt = self.phys_offset + phys.as_u64();
+        unsafe { &*virt.as_ptr() }
+    }
+}
+
+// returns the flags of the lower level 'entry' combined with 'flags' of the
+// levels above it
+fn combine(flags: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let restricting = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let combined = (entry - restricting) | (entry & flags & restricting);
+    combined | (flags & PageTableFlags::NO_EXECUTE)
+}
+
+// consecutive pages that are printed as one line
+struct Run {
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+}
+
+impl Run {
+    fn extends(&self, start: VirtAddr, phys: PhysAddr, flags: PageTableFlags) -> bool {
+        self.start.as_u64() + self.size == start.as_u64()
+            && self.phys + self.size == phys
+            && self.flags == flags
+    }
+}
+
+fn print_run(run: &mut Option<Run>) {
+    if let Some(r) = run.take() {
+        serial_println!(
+            "        {:#x}..{:#x} -> {:#x} {:?}",
+            r.start.as_u64(), r.start.as_u64() + r.size, r.phys.as_u64(), r.flags
+        );
+    }
+}
@@ -1,15 +1,32 @@
 // src/task/executor.rs
 
 use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
+use alloc::{sync::Arc, vec::Vec};
 use core::task::Waker;
 use crossbeam_queue::ArrayQueue;
 use core::task::{Context, Poll};
 
+/// Error returned when a task could not be spawned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// There is not enough heap memory to track the task.
+    OutOfMemory,
+    /// The task queue has no room for the task.
+    QueueFull,
+}
+
+/// A spawned task together with the waker that schedules it again.
+struct TaskEntry {
+    id: TaskId,
+    task: Task,
+    waker: Waker,
+}
+
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
+    // sorted by task ID, a Vec so that room for a task can be reserved
+    // fallibly, which BTreeMap does not support
+    tasks: Vec<TaskEntry>,
     task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
 }
 
 impl Executor {
@@ -18,21 +35,42 @@ impl Executor {
     /// Returns the created executor.
     pub fn new() -> Self {
         Executor {
-            tasks: BTreeMap::new(),
+            tasks: Vec::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
         }
     }
 
     /// Spawns a new task by adding it to the executor's task list and task queue.
     /// 
     /// Takes ownership of the task to be spawned.
+    /// Panics if the task cannot be spawned, see `try_spawn`.
     pub fn spawn(&mut self, task: Task) {
+        if let Err(err) = self.try_spawn(task) {
+            panic!("failed to spawn task: {:?}", err);
+        }
+    }
+
+    /// Spawns a new task like `spawn`, but fails instead of panicking.
+    /// 
+    /// Returns an error if there is not enough memory for the task or the task
+    /// queue is full. The task is dropped in that case.
+    pub fn try_spawn(&mut self, task: Task) -> Result<(), SpawnError> {
         let task_id = task.id;
-        if self.tasks.insert(task_id, task).is_some() {
-            panic!("Task with same ID already exists");
+        let index = match self.tasks.binary_search_by_key(&task_id, |entry| entry.id) {
+            Ok(_) => panic!("Task with same ID already exists"),
+            Err(index) => index,
+        };
+        // allocate everything before the task is stored
+        self.tasks.try_reserve(1).map_err(|_| SpawnError::OutOfMemory)?;
+        let waker = TaskWaker::try_new(task_id, self.task_queue.clone())
+            .ok_or(SpawnError::OutOfMemory)?;
+        // cannot allocate, the slot was reserved above
+        self.tasks.insert(index, TaskEntry { id: task_id, task, waker });
+        if self.task_queue.push(task_id).is_err() {
+            self.tasks.remove(index);
+            return Err(SpawnError::QueueFull);
         }
-        self.task_queue.push(task_id).expect("queue full");
+        Ok(())
     }
 
     /// Runs ready tasks until completion.
@@ -43,23 +81,19 @@ impl Executor {
         let Self {
             tasks,
             task_queue,
-            waker_cache,
         } = self;
 
         while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, //task no longer exists
+            let index = match tasks.binary_search_by_key(&task_id, |entry| entry.id) {
+                Ok(index) => index,
+                Err(_) => continue, //task no longer exists
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+            let TaskEntry { task, waker, .. } = &mut tasks[index];
             let mut context = Context::from_waker(waker);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    // task done -> remove it together with its waker
+                    tasks.remove(index);
                 }
                 Poll::Pending => {} // task not done -> do nothing
             }
@@ -104,8 +138,10 @@ impl Wake for TaskWaker {
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker { task_id, task_queue}))
+    /// Returns `None` if there is not enough memory for the waker.
+    fn try_new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Option<Waker> {
+        let waker = Arc::try_new(TaskWaker { task_id, task_queue }).ok()?;
+        Some(Waker::from(waker))
     }
 
     fn wake_task(&self) {
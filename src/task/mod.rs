@@ -1,6 +1,6 @@
 // src/task/mod.rs
 
-use core::{future::Future, pin::Pin};
+use core::{alloc::AllocError, future::Future, pin::Pin};
 use alloc::boxed::Box;
 use core::task::{Context, Poll};
 
@@ -27,19 +27,47 @@ impl Task {
         }
     }
 
+    /// Creates a new Task like `new`, but fails instead of aborting.
+    /// 
+    /// Returns an error if there is not enough heap memory for the future.
+    pub fn try_new(future: impl Future<Output = ()> + 'static) -> Result<Task, AllocError> {
+        Ok(Task {
+            id: TaskId::new(),
+            future: Box::into_pin(Box::try_new(future)?),
+        })
+    }
+
     /// Polls the task's future to make progress.
     /// 
     /// returns Poll<()>, indicating whether the future is ready or pending.
+    /// The task is reported by `current_task_id` while it is polled.
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let previous = CURRENT_TASK.swap(self.id.0, Ordering::Relaxed);
+        let result = self.future.as_mut().poll(context);
+        CURRENT_TASK.store(previous, Ordering::Relaxed);
+        result
     }
 }
 
+/// Unique identifier of a task.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 use core::sync::atomic::{AtomicU64, Ordering};
 
+/// ID of the task that is currently polled, `u64::MAX` if none is.
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Returns the ID of the task that is currently polled by an executor.
+///
+/// Returns `None` when called outside of a task, e.g. from the kernel main loop.
+pub fn current_task_id() -> Option<TaskId> {
+    match CURRENT_TASK.load(Ordering::Relaxed) {
+        u64::MAX => None,
+        id => Some(TaskId(id)),
+    }
+}
+
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
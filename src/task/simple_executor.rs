@@ -1,6 +1,6 @@
 // src/task/simple_executor.rs
 
-use super::Task;
+use super::{executor::SpawnError, Task};
 use alloc::collections::VecDeque;
 use core::task::{Waker, RawWaker};
 use core::task::RawWakerVTable;
@@ -27,6 +27,15 @@ impl SimpleExecutor {
         self.task_queue.push_back(task)
     }
 
+    /// Spawns a new task like `spawn`, but fails instead of aborting.
+    /// 
+    /// Returns an error if there is not enough memory to queue the task.
+    pub fn try_spawn(&mut self, task: Task) -> Result<(), SpawnError> {
+        self.task_queue.try_reserve(1).map_err(|_| SpawnError::OutOfMemory)?;
+        self.task_queue.push_back(task);
+        Ok(())
+    }
+
     /// Runs the executor until all tasks are complete.
     /// 
     /// this method takes the first task from the queue, polls it, and if it's not complete,
@@ -0,0 +1,209 @@
+// tests/address_space.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use capeos::cpu;
+use capeos::memory::{
+    self,
+    address_space::{self, AddressSpace, AddressSpaceError},
+    walk::PageTableWalker,
+};
+use core::panic::PanicInfo;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+// a page in the lower half that is not used by the kernel
+const USER_PAGE: u64 = 0x_7000_0000_0000;
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+fn new_space() -> AddressSpace {
+    memory::with_kernel_memory(AddressSpace::new)
+        .unwrap()
+        .expect("failed to create address space")
+}
+
+fn allocate_frame() -> PhysFrame {
+    memory::with_kernel_memory(|m| m.frame_allocator.allocate_frame())
+        .unwrap()
+        .expect("out of frames")
+}
+
+fn deallocate_frame(frame: PhysFrame) {
+    memory::with_kernel_memory(|m| unsafe { m.frame_allocator.deallocate_frame(frame) });
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+fn map_user_page(space: &mut AddressSpace, frame: PhysFrame) {
+    let page = Page::containing_address(VirtAddr::new(USER_PAGE));
+    memory::with_kernel_memory(|m| space.map_user_page(m, page, frame, FLAGS))
+        .unwrap()
+        .expect("failed to map user page");
+}
+
+fn activate_kernel() {
+    memory::with_kernel_memory(address_space::activate_kernel);
+}
+
+#[test_case]
+fn kernel_stays_mapped() {
+    let kernel_frame = Cr3::read().0;
+    let boxed = Box::new(41);
+    let space = new_space();
+    assert_ne!(space.level_4_frame(), kernel_frame);
+
+    space.activate();
+    assert!(space.is_active());
+    // heap, stack and code are all still there
+    let other = Box::new(1);
+    assert_eq!(*boxed + *other, 42);
+
+    activate_kernel();
+    assert_eq!(Cr3::read().0, kernel_frame);
+}
+
+#[test_case]
+fn user_pages_are_private() {
+    let frame = allocate_frame();
+    let mut space = new_space();
+    map_user_page(&mut space, frame);
+
+    let user = VirtAddr::new(USER_PAGE);
+    let translation = space.translate(user).expect("user page not mapped");
+    assert_eq!(translation.phys_addr, frame.start_address());
+    assert!(translation.flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+
+    // the kernel page tables do not contain the page
+    let in_kernel = memory::with_kernel_memory(|m| PageTableWalker::new(&mut m.mapper).translate(user))
+        .unwrap();
+    assert!(in_kernel.is_none());
+
+    let data = *b"own address space";
+    let mut read = [0u8; 17];
+    space.activate();
+    unsafe {
+        cpu::copy_to_user(USER_PAGE as *mut u8, &data).unwrap();
+        cpu::copy_from_user(&mut read, USER_PAGE as *const u8).unwrap();
+    }
+    activate_kernel();
+    assert_eq!(read, data);
+
+    drop(space);
+    deallocate_frame(frame);
+}
+
+#[test_case]
+fn same_address_in_two_spaces() {
+    let frames = [allocate_frame(), allocate_frame()];
+    let mut spaces = [new_space(), new_space()];
+    for (space, frame) in spaces.iter_mut().zip(frames) {
+        map_user_page(space, frame);
+    }
+
+    for (i, space) in spaces.iter().enumerate() {
+        space.activate();
+        unsafe { cpu::copy_to_user(USER_PAGE as *mut u8, &[i as u8]).unwrap() };
+    }
+    for (i, space) in spaces.iter().enumerate() {
+        let mut read = [0u8];
+        space.activate();
+        unsafe { cpu::copy_from_user(&mut read, USER_PAGE as *const u8).unwrap() };
+        assert_eq!(read[0], i as u8);
+    }
+    activate_kernel();
+
+    drop(spaces);
+    frames.into_iter().for_each(deallocate_frame);
+}
+
+#[test_case]
+fn kernel_pages_cannot_be_mapped() {
+    use capeos::allocator::HEAP_START;
+
+    let frame = allocate_frame();
+    let mut space = new_space();
+    let page = Page::containing_address(VirtAddr::new(HEAP_START as u64));
+    let result = memory::with_kernel_memory(|m| space.map_user_page(m, page, frame, FLAGS)).unwrap();
+    assert!(matches!(result, Err(AddressSpaceError::KernelAddress)));
+
+    drop(space);
+    deallocate_frame(frame);
+}
+
+#[test_case]
+fn drop_frees_all_page_tables() {
+    // create the shared kernel entries first, they are never freed
+    drop(new_space());
+
+    let kernel_frame = Cr3::read().0;
+    let frame = allocate_frame();
+    let free = free_frames();
+    let mut space = new_space();
+    map_user_page(&mut space, frame);
+    // a level 4 table and a level 3, 2 and 1 table for the user page
+    assert_eq!(free - free_frames(), 4);
+
+    space.activate();
+    drop(space);
+    assert_eq!(free_frames(), free);
+    // dropping the active address space switches back to the kernel
+    assert_eq!(Cr3::read().0, kernel_frame);
+    deallocate_frame(frame);
+}
+
+#[test_case]
+fn destroy_inside_kernel_memory() {
+    drop(new_space());
+
+    let free = free_frames();
+    memory::with_kernel_memory(|m| {
+        let frame = m.frame_allocator.allocate_frame().expect("out of frames");
+        let mut space = AddressSpace::new(m).expect("failed to create address space");
+        let page = Page::containing_address(VirtAddr::new(USER_PAGE));
+        space.map_user_page(m, page, frame, FLAGS).expect("failed to map user page");
+        space.destroy(m);
+        unsafe { m.frame_allocator.deallocate_frame(frame) };
+    })
+    .unwrap();
+    assert_eq!(free_frames(), free);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
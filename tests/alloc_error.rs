@@ -0,0 +1,48 @@
+// tests/alloc_error.rs
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use capeos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("alloc_error::alloc_error... \t");
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    alloc_error();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    capeos::hlt_loop();
+}
+
+fn alloc_error() {
+    // larger than the heap can ever grow, so the allocation error handler runs
+    let vec: Vec<u8> = Vec::with_capacity(capeos::allocator::HEAP_MAX_SIZE + 1);
+    core::hint::black_box(vec);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    capeos::hlt_loop();
+}
@@ -0,0 +1,96 @@
+// tests/apic.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use capeos::{
+    acpi, apic,
+    interrupts::{self, KEYBOARD_IRQ, PICS, PIC_1_OFFSET, TIMER_IRQ},
+    memory,
+};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    interrupts::init_apic().expect("failed to switch to the APIC");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+#[test_case]
+fn madt_is_found() {
+    let phys_offset = memory::with_kernel_memory(|m| m.mapper.phys_offset()).unwrap();
+    let madt = acpi::find_madt(phys_offset).expect("no MADT");
+    assert!(madt.io_apic.is_some());
+    // QEMU connects the PIT to line 2 of the I/O APIC
+    assert_eq!(madt.isa_irq(TIMER_IRQ).gsi, 2);
+    // so the cascade line is not connected
+    assert!(madt.isa_irq_replaced(2));
+    assert!(!madt.isa_irq_replaced(TIMER_IRQ));
+    assert_eq!(madt.isa_irq(KEYBOARD_IRQ).gsi, 1);
+}
+
+#[test_case]
+fn pics_are_disabled() {
+    assert!(apic::supported() && apic::is_enabled());
+    let masks = unsafe { PICS.lock().read_masks() };
+    assert_eq!(masks, [0xff, 0xff]);
+}
+
+#[test_case]
+fn keyboard_and_timer_are_routed() {
+    let keyboard = apic::isa_irq_redirection(KEYBOARD_IRQ).unwrap();
+    assert_eq!(keyboard & 0xff, u64::from(PIC_1_OFFSET + KEYBOARD_IRQ));
+    assert_eq!(keyboard & REDIRECTION_MASKED, 0);
+    assert_eq!((keyboard >> 56) as u8, apic::local_apic_id().unwrap());
+
+    // the local APIC timer was calibrated and replaces the PIT
+    assert!(apic::timer_running());
+    let timer = apic::isa_irq_redirection(TIMER_IRQ).unwrap();
+    assert_eq!(timer & 0xff, u64::from(PIC_1_OFFSET + TIMER_IRQ));
+    assert_ne!(timer & REDIRECTION_MASKED, 0);
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn count_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn local_apic_timer_ticks() {
+    interrupts::register_irq(TIMER_IRQ, count_tick).unwrap();
+    // the timer keeps ticking, so the end of interrupt reaches the local APIC
+    while TICKS.load(Ordering::Relaxed) < 3 {
+        x86_64::instructions::hlt();
+    }
+    // registering a handler must not unmask the PIT
+    let timer = apic::isa_irq_redirection(TIMER_IRQ).unwrap();
+    assert_ne!(timer & REDIRECTION_MASKED, 0);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
@@ -0,0 +1,99 @@
+// tests/buddy_allocation.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use capeos::memory::buddy::{BuddyFrameAllocator, ORDER_2MIB};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB},
+    PhysAddr,
+    VirtAddr,
+};
+
+static BUDDY_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+static PHYS_MEM_OFFSET: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *BUDDY_ALLOCATOR.lock() = Some(frame_allocator);
+    *PHYS_MEM_OFFSET.lock() = Some(phys_mem_offset);
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+#[test_case]
+fn order_allocation_is_aligned() {
+    let mut guard = BUDDY_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    for order in 0..=4 {
+        let frame = allocator.allocate_order(order).expect("out of frames");
+        assert!(frame.start_address().is_aligned(4096u64 << order));
+        assert_eq!(allocator.free_frames(), free - (1 << order));
+        unsafe { allocator.deallocate_order(frame, order) };
+        assert_eq!(allocator.free_frames(), free);
+    }
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    let mut guard = BUDDY_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().expect("no 2 MiB frame");
+    assert!(frame.start_address().is_aligned(2 * 1024 * 1024u64));
+    assert_eq!(allocator.free_frames(), free - (1 << ORDER_2MIB));
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn buddies_are_merged() {
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let mut guard = BUDDY_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    // exhaust physical memory with single frames, chaining them through their
+    // first word so that no extra storage is needed to free them again
+    let mut head: Option<PhysFrame> = None;
+    while let Some(frame) = allocator.allocate_order(0) {
+        let link: *mut u64 = (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { link.write(head.map_or(u64::MAX, |f| f.start_address().as_u64())) };
+        head = Some(frame);
+    }
+    assert_eq!(allocator.free_frames(), 0);
+    assert!(allocator.allocate_order(ORDER_2MIB).is_none());
+
+    while let Some(frame) = head {
+        let link: *const u64 = (phys_mem_offset + frame.start_address().as_u64()).as_ptr();
+        let next = unsafe { link.read() };
+        head = (next != u64::MAX).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+        unsafe { allocator.deallocate_order(frame, 0) };
+    }
+    assert_eq!(allocator.free_frames(), free);
+
+    // only possible if the freed frames were merged back into large blocks
+    let block = allocator.allocate_order(ORDER_2MIB).expect("buddies were not merged");
+    unsafe { allocator.deallocate_order(block, ORDER_2MIB) };
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
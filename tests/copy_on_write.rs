@@ -0,0 +1,218 @@
+// tests/copy_on_write.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use capeos::memory::{
+    self,
+    cow::COPY_ON_WRITE,
+    region::{Region, RegionKind},
+    walk::{PageTableWalker, Translation},
+};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+const PAGES: u64 = 2;
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+fn translate(addr: VirtAddr) -> Translation {
+    memory::with_kernel_memory(|m| PageTableWalker::new(&mut m.mapper).translate(addr))
+        .unwrap()
+        .expect("page not mapped")
+}
+
+fn ref_count(addr: VirtAddr) -> u16 {
+    let frame = PhysFrame::containing_address(translate(addr).phys_addr);
+    memory::with_kernel_memory(|m| m.frame_refs.get(frame)).unwrap()
+}
+
+fn write(addr: VirtAddr, value: u64) {
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(value) };
+}
+
+fn read(addr: VirtAddr) -> u64 {
+    unsafe { addr.as_ptr::<u64>().read_volatile() }
+}
+
+// maps a region and writes the page number into each page
+fn map_filled() -> Region {
+    let region = memory::with_kernel_memory(|m| m.map_region(PAGES * 4096, RegionKind::Buffer, FLAGS))
+        .unwrap()
+        .expect("mapping failed");
+    for page in 0..PAGES {
+        write(region.start + page * 4096, page + 1);
+    }
+    region
+}
+
+fn clone(region: &Region) -> Region {
+    memory::with_kernel_memory(|m| m.clone_region_copy_on_write(region.start))
+        .unwrap()
+        .expect("clone failed")
+}
+
+fn unmap(region: Region) {
+    memory::with_kernel_memory(|m| m.unmap_region(region.start)).unwrap().unwrap();
+}
+
+#[test_case]
+fn shared_pages_are_read_only() {
+    let original = map_filled();
+    let copy = clone(&original);
+
+    for page in 0..PAGES {
+        let offset = page * 4096;
+        let a = translate(original.start + offset);
+        let b = translate(copy.start + offset);
+        assert_eq!(a.phys_addr, b.phys_addr);
+        for t in [a, b] {
+            assert!(!t.flags.contains(PageTableFlags::WRITABLE));
+            assert!(t.flags.contains(COPY_ON_WRITE));
+            assert!(t.flags.contains(PageTableFlags::NO_EXECUTE));
+        }
+        assert_eq!(ref_count(original.start + offset), 2);
+    }
+    unmap(copy);
+    unmap(original);
+}
+
+#[test_case]
+fn shared_pages_read_the_same_data() {
+    let original = map_filled();
+    let free = free_frames();
+    let copy = clone(&original);
+
+    for page in 0..PAGES {
+        let offset = page * 4096;
+        assert_eq!(read(original.start + offset), page + 1);
+        assert_eq!(read(copy.start + offset), page + 1);
+    }
+    // reading does not copy, only page tables may have been allocated
+    assert!(free - free_frames() <= 3);
+    unmap(copy);
+    unmap(original);
+}
+
+#[test_case]
+fn write_copies_the_page() {
+    let original = map_filled();
+    let copy = clone(&original);
+    let shared = translate(original.start).phys_addr;
+
+    let free = free_frames();
+    write(copy.start, 42);
+    assert_eq!(free - free_frames(), 1);
+
+    assert_eq!(read(copy.start), 42);
+    assert_eq!(read(original.start), 1);
+    let copied = translate(copy.start);
+    assert_ne!(copied.phys_addr, shared);
+    assert!(copied.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!copied.flags.contains(COPY_ON_WRITE));
+    assert_eq!(ref_count(copy.start), 0);
+    assert_eq!(ref_count(original.start), 1);
+
+    // the other page is still shared
+    assert_eq!(translate(copy.start + 4096u64).phys_addr, translate(original.start + 4096u64).phys_addr);
+    unmap(copy);
+    unmap(original);
+}
+
+#[test_case]
+fn last_mapping_keeps_its_frame() {
+    let original = map_filled();
+    let copy = clone(&original);
+    write(copy.start, 42);
+
+    // the original is the only mapping left, so no copy is needed
+    let frame = translate(original.start).phys_addr;
+    let free = free_frames();
+    write(original.start, 7);
+    assert_eq!(free_frames(), free);
+    let t = translate(original.start);
+    assert_eq!(t.phys_addr, frame);
+    assert!(t.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!t.flags.contains(COPY_ON_WRITE));
+    assert_eq!(read(original.start), 7);
+    assert_eq!(read(copy.start), 42);
+    unmap(copy);
+    unmap(original);
+}
+
+#[test_case]
+fn shared_frames_are_freed_once() {
+    let free = free_frames();
+    let original = map_filled();
+    let copy = clone(&original);
+    write(copy.start, 42);
+
+    // frames still used by the original are kept
+    let before_unmap = free_frames();
+    unmap(copy);
+    assert_eq!(free_frames(), before_unmap + 1);
+    assert_eq!(read(original.start + 4096u64), 2);
+    assert_eq!(ref_count(original.start + 4096u64), 1);
+
+    unmap(original);
+    // only page tables may stay allocated
+    assert!(free - free_frames() <= 3);
+}
+
+#[test_case]
+fn failed_share_is_undone() {
+    let original = map_filled();
+    // the destination is mapped already, so mapping the shared frame fails
+    let taken = map_filled();
+    let result = memory::with_kernel_memory(|m| m.share_copy_on_write(original.start, taken.start, 4096))
+        .unwrap();
+    assert!(result.is_err());
+
+    let t = translate(original.start);
+    assert!(t.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!t.flags.contains(COPY_ON_WRITE));
+    assert!(ref_count(original.start) <= 1);
+
+    // the frame is freed with its only mapping
+    unmap(taken);
+    let free = free_frames();
+    unmap(original);
+    assert_eq!(free_frames(), free + PAGES as usize);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
@@ -0,0 +1,117 @@
+// tests/demand_paging.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use capeos::memory::{
+    self,
+    region::{Region, RegionKind},
+    walk::PageTableWalker,
+};
+use core::panic::PanicInfo;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_kernel_memory(|m| PageTableWalker::new(&mut m.mapper).translate(addr).is_some())
+        .unwrap()
+}
+
+fn reserve(size: u64) -> Region {
+    memory::with_kernel_memory(|m| m.reserve_demand_zero(size, RegionKind::Buffer, FLAGS))
+        .unwrap()
+        .expect("reservation failed")
+}
+
+fn unmap(region: Region) {
+    memory::with_kernel_memory(|m| m.unmap_region(region.start)).unwrap().unwrap();
+}
+
+#[test_case]
+fn pages_are_zeroed_on_first_touch() {
+    let region = reserve(4 * 4096);
+    assert!(!is_mapped(region.start));
+
+    let words: *mut u64 = region.start.as_mut_ptr();
+    let count = 4096 / 8;
+    unsafe {
+        assert!((0..count).all(|i| words.add(i).read_volatile() == 0));
+        words.write_volatile(7);
+        assert_eq!(words.read_volatile(), 7);
+    }
+    assert!(is_mapped(region.start));
+    assert!(!is_mapped(region.start + 4096u64));
+    unmap(region);
+}
+
+#[test_case]
+fn reserve_more_than_physical_memory() {
+    use capeos::memory::region::KERNEL_VM_SIZE;
+
+    let physical = memory::with_kernel_memory(|m| m.frame_allocator.total_frames()).unwrap() as u64 * 4096;
+    let size = (physical * 8).min(KERNEL_VM_SIZE / 2);
+    assert!(size > physical);
+    let region = reserve(size);
+    assert_eq!(region.size, size);
+
+    // pages far apart, each one needs its own page tables
+    let touched = [
+        region.start,
+        region.start + size / 2,
+        region.start + (size - 4096),
+    ];
+    let free = free_frames();
+    for addr in touched {
+        unsafe { addr.as_mut_ptr::<u64>().write_volatile(addr.as_u64()) };
+    }
+    let used = free - free_frames();
+    // one frame per touched page, plus at most a level 3, 2 and 1 table for each
+    assert!(used >= touched.len() && used <= touched.len() * 4);
+
+    for addr in touched {
+        assert!(is_mapped(addr));
+        assert_eq!(unsafe { addr.as_ptr::<u64>().read_volatile() }, addr.as_u64());
+    }
+    assert!(!is_mapped(region.start + 4096u64));
+    assert!(!is_mapped(region.start + size / 4));
+
+    // the touched frames go back, the page tables stay
+    let before_unmap = free_frames();
+    unmap(region);
+    assert_eq!(free_frames(), before_unmap + touched.len());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
@@ -0,0 +1,48 @@
+// tests/divide_error.rs
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use capeos::{exit_qemu, interrupts, serial_print, serial_println, QemuExitCode};
+
+// vector of a divide error (#DE)
+const VECTOR: u8 = 0;
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    serial_print!("divide_error::divide_error... \t");
+
+    capeos::init();
+    // the compiler checks divisions by zero itself, so divide in assembly
+    unsafe {
+        asm!(
+            "xor edx, edx",
+            "div {divisor}",
+            divisor = in(reg) 0u64,
+            inout("rax") 1u64 => _,
+            out("rdx") _,
+            options(nomem, nostack),
+        )
+    };
+
+    panic!("Execution continued after divide error");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // the exception must have been reported under its own name, not as a double fault
+    if interrupts::last_exception() == Some(VECTOR)
+        && interrupts::exception_name(VECTOR) == Some("DIVIDE ERROR")
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        serial_println!("reported exception: {:?}\n", interrupts::last_exception());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    capeos::hlt_loop();
+}
@@ -0,0 +1,51 @@
+// tests/double_free.rs
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use capeos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("double_free::double_free... \t");
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    double_free();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    capeos::hlt_loop();
+}
+
+fn double_free() {
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    capeos::hlt_loop();
+}
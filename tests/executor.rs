@@ -0,0 +1,105 @@
+// tests/executor.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use capeos::task::{
+    executor::{Executor, SpawnError},
+    Task,
+};
+use core::{panic::PanicInfo, ptr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+// the capacity of the executor's task queue
+const TASK_QUEUE_SIZE: usize = 100;
+
+async fn empty_task() {}
+
+#[test_case]
+fn try_spawn_succeeds() {
+    let mut executor = Executor::new();
+    let task = Task::try_new(empty_task()).expect("out of memory");
+    assert_eq!(executor.try_spawn(task), Ok(()));
+}
+
+#[test_case]
+fn try_spawn_fails_when_queue_is_full() {
+    let mut executor = Executor::new();
+    for _ in 0..TASK_QUEUE_SIZE {
+        assert_eq!(executor.try_spawn(Task::new(empty_task())), Ok(()));
+    }
+    let result = executor.try_spawn(Task::new(empty_task()));
+    assert_eq!(result, Err(SpawnError::QueueFull));
+}
+
+// allocates blocks from large to small until the heap is full and returns them
+// as a list that is chained through the first word of each block
+fn exhaust_heap() -> *mut u8 {
+    let mut head = ptr::null_mut::<u8>();
+    let mut size = 1 << 20;
+    while size >= 16 {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        loop {
+            let block = unsafe { alloc(layout) };
+            if block.is_null() {
+                break;
+            }
+            unsafe { block.cast::<(*mut u8, usize)>().write((head, size)) };
+            head = block;
+        }
+        size /= 2;
+    }
+    head
+}
+
+fn free_blocks(mut head: *mut u8) {
+    while !head.is_null() {
+        let (next, size) = unsafe { head.cast::<(*mut u8, usize)>().read() };
+        unsafe { dealloc(head, Layout::from_size_align(size, 8).unwrap()) };
+        head = next;
+    }
+}
+
+// runs last, the bump allocator never gets the memory back
+#[test_case]
+fn try_spawn_fails_without_memory() {
+    let mut executor = Executor::new();
+    let task = Task::new(empty_task());
+
+    let blocks = exhaust_heap();
+    // the task list and the waker both need memory
+    let result = executor.try_spawn(task);
+    free_blocks(blocks);
+    assert_eq!(result, Err(SpawnError::OutOfMemory));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
@@ -0,0 +1,104 @@
+// tests/frame_allocation.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{bootinfo::MemoryMap, entry_point, BootInfo};
+use capeos::memory::{bitmap::BitmapFrameAllocator, BootInfoFrameAllocator};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+
+// number of frames allocated by the bulk allocation tests
+const MANY_FRAMES: usize = 20_000;
+
+static MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+static BITMAP_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+static ALLOCATED: Mutex<[Option<PhysFrame>; MANY_FRAMES]> = Mutex::new([None; MANY_FRAMES]);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *BITMAP_ALLOCATOR.lock() = Some(frame_allocator);
+    *MEMORY_MAP.lock() = Some(&boot_info.memory_map);
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+#[test_case]
+fn bitmap_counts_frames() {
+    let mut guard = BITMAP_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+    assert!(free > 0);
+    assert_eq!(allocator.free_frames() + allocator.used_frames(), allocator.total_frames());
+
+    let frame = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(allocator.free_frames(), free - 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn bitmap_reuses_freed_frame() {
+    let mut guard = BITMAP_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let first = allocator.allocate_frame().unwrap();
+    let second = allocator.allocate_frame().unwrap();
+    assert_ne!(first, second);
+
+    unsafe { allocator.deallocate_frame(first) };
+    assert_eq!(allocator.allocate_frame(), Some(first));
+
+    unsafe {
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+}
+
+#[test_case]
+fn boot_info_many_frames() {
+    // frames are only counted, never written, so this does not disturb the bitmap
+    let memory_map = MEMORY_MAP.lock().unwrap();
+    let mut allocator = unsafe { BootInfoFrameAllocator::init(memory_map) };
+    let mut previous = allocator.allocate_frame().expect("out of frames");
+    for _ in 1..MANY_FRAMES {
+        let frame = allocator.allocate_frame().expect("out of frames");
+        assert!(frame > previous);
+        previous = frame;
+    }
+}
+
+#[test_case]
+fn bitmap_many_frames() {
+    let mut guard = BITMAP_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let mut allocated = ALLOCATED.lock();
+    let free = allocator.free_frames();
+
+    for slot in allocated.iter_mut() {
+        *slot = Some(allocator.allocate_frame().expect("out of frames"));
+    }
+    assert_eq!(allocator.free_frames(), free - MANY_FRAMES);
+
+    for slot in allocated.iter_mut() {
+        unsafe { allocator.deallocate_frame(slot.take().unwrap()) };
+    }
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
@@ -0,0 +1,38 @@
+// tests/general_protection_fault.rs
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use capeos::{exit_qemu, interrupts, serial_print, serial_println, QemuExitCode};
+
+// vector of a general protection fault (#GP)
+const VECTOR: u8 = 13;
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    serial_print!("general_protection_fault::general_protection_fault... \t");
+
+    capeos::init();
+    // a non-canonical address is not a page fault but a general protection fault
+    unsafe { (0xdead_beef_0000_0000 as *mut u64).write_volatile(42) };
+
+    panic!("Execution continued after general protection fault");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // the exception must have been reported under its own name, not as a double fault
+    if interrupts::last_exception() == Some(VECTOR)
+        && interrupts::exception_name(VECTOR) == Some("GENERAL PROTECTION FAULT")
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        serial_println!("reported exception: {:?}\n", interrupts::last_exception());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    capeos::hlt_loop();
+}
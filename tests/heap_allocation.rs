@@ -12,22 +12,22 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use capeos::allocator::HEAP_SIZE;
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use capeos::allocator;
-    use capeos::memory::{self, BootInfoFrameAllocator};
+    use capeos::memory::{self, buddy::BuddyFrameAllocator};
     use x86_64::VirtAddr;
 
     capeos::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset)};
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    let mapper = unsafe { memory::init(phys_mem_offset)};
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap()
         .expect("heap initialization failed");
 
     test_main();
@@ -52,8 +52,13 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
 }
 
+// the bump allocator only reuses memory once every allocation is freed,
+// so the long lived box keeps it from ever resetting
+#[cfg(not(feature = "alloc-bump"))]
 #[test_case]
 fn many_boxes() {
+    use capeos::allocator::HEAP_SIZE;
+
     let long_lived = Box::new(1);
     for i in 0..HEAP_SIZE {
         let x = Box::new(i);
@@ -62,6 +67,177 @@ fn many_boxes() {
     assert_eq!(*long_lived, 1);
 }
 
+// linked_list_allocator does not count allocations
+#[cfg(not(feature = "alloc-external"))]
+#[test_case]
+fn stats_track_allocations() {
+    use capeos::allocator::heap_stats;
+
+    let before = heap_stats();
+    let value = Box::new([0u64; 16]);
+    let during = heap_stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.used >= before.used + 128);
+    assert!(during.peak_used >= during.used);
+    assert_eq!(during.used + during.free(), during.heap_size);
+
+    drop(value);
+    assert_eq!(heap_stats().allocations, before.allocations);
+}
+
+// the debug allocator moves allocations into a larger block size class
+#[cfg(all(feature = "alloc-fixed-block", not(feature = "alloc-debug")))]
+#[test_case]
+fn block_class_stats() {
+    use capeos::allocator::{block_stats, fixed_size_block::BlockClassStats};
+
+    fn class_128() -> BlockClassStats {
+        block_stats().into_iter().find(|c| c.block_size == 128).unwrap()
+    }
+    let before = class_128();
+    let value = Box::new([0u64; 16]);
+    let during = class_128();
+    assert_eq!(during.allocated, before.allocated + 1);
+
+    drop(value);
+    let after = class_128();
+    assert_eq!(after.allocated, before.allocated);
+    assert_eq!(after.free, during.free + 1);
+}
+
+#[cfg(all(feature = "alloc-fixed-block", not(feature = "alloc-debug")))]
+#[test_case]
+fn empty_slabs_are_released() {
+    use capeos::allocator::block_stats;
+
+    // a burst of 8 byte allocations spread over many slabs
+    let boxes: Vec<Box<u64>> = (0..4096).map(Box::new).collect();
+    assert!(block_stats()[0].slabs >= 8);
+
+    drop(boxes);
+    assert!(block_stats()[0].slabs <= 1);
+}
+
+// only the fixed size block allocator grows the heap
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn grow_past_initial_size() {
+    use alloc::vec;
+    use capeos::allocator::HEAP_SIZE;
+
+    // a single allocation larger than the initial heap
+    let big = vec![1u8; 4 * HEAP_SIZE];
+    assert_eq!(big.iter().map(|&b| b as usize).sum::<usize>(), 4 * HEAP_SIZE);
+}
+
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn many_large_boxes() {
+    // keep about 1 MiB alive at the same time
+    let boxes: Vec<Box<[u8; 16 * 1024]>> = (0..64u8)
+        .map(|i| Box::new([i; 16 * 1024]))
+        .collect();
+    for (i, b) in boxes.iter().enumerate() {
+        assert!(b.iter().all(|&x| x as usize == i));
+    }
+}
+
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn growth_limit() {
+    use capeos::allocator::HEAP_MAX_SIZE;
+
+    // requests beyond the heap limit fail instead of mapping more pages
+    let layout = core::alloc::Layout::from_size_align(HEAP_MAX_SIZE + 1, 8).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(ptr.is_null());
+}
+
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn no_growth_under_kernel_memory_lock() {
+    use capeos::{allocator::heap_stats, memory};
+
+    // more than the mapped heap has left, so the heap has to grow
+    let size = heap_stats().free() + 4096;
+    let locked = memory::with_kernel_memory(|_| Vec::<u8>::new().try_reserve_exact(size).is_ok())
+        .unwrap();
+    assert!(!locked);
+
+    let mut unlocked = Vec::<u8>::new();
+    assert!(unlocked.try_reserve_exact(size).is_ok());
+}
+
+// without merging, the freed blocks stay separate small regions
+#[cfg(feature = "alloc-linked-list")]
+#[test_case]
+fn freed_regions_coalesce() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+    use capeos::allocator::HEAP_SIZE;
+
+    // fill three quarters of the heap with small blocks
+    let mut blocks: Vec<Option<Box<[u8; 1024]>>> = (0..HEAP_SIZE / 1024 * 3 / 4)
+        .map(|_| Some(Box::new([0; 1024])))
+        .collect();
+    // free every other block first, then the rest
+    for block in blocks.iter_mut().step_by(2) {
+        block.take();
+    }
+    drop(blocks);
+
+    // only fits if the freed blocks were merged again
+    let layout = Layout::from_size_align(HEAP_SIZE / 2, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { dealloc(ptr, layout) };
+}
+
+#[cfg(feature = "alloc-debug")]
+#[test_case]
+fn freed_memory_is_poisoned() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+    use capeos::allocator::debug::POISON;
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.write_bytes(0, layout.size());
+        dealloc(ptr, layout);
+        // the block is not reused in between, so it still holds the poison
+        let freed = core::slice::from_raw_parts(ptr, layout.size());
+        assert!(freed.iter().all(|&b| b == POISON));
+    }
+}
+
+// the bump allocator cannot reuse the memory the hook frees
+#[cfg(not(feature = "alloc-bump"))]
+#[test_case]
+fn reclaim_hook_frees_memory() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+    use capeos::allocator::oom::register_reclaim_hook;
+    use spin::Mutex;
+
+    #[cfg(feature = "alloc-fixed-block")]
+    const SIZE: usize = capeos::allocator::HEAP_MAX_SIZE / 8 * 5;
+    #[cfg(not(feature = "alloc-fixed-block"))]
+    const SIZE: usize = capeos::allocator::HEAP_SIZE / 8 * 5;
+
+    // a cache that holds most of the heap until memory runs out
+    static CACHE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+    fn drop_cache(_layout: Layout) -> bool {
+        CACHE.lock().take().is_some()
+    }
+
+    *CACHE.lock() = Some(Vec::with_capacity(SIZE));
+    register_reclaim_hook(drop_cache).expect("reclaim hook table full");
+
+    // only fits once the hook dropped the cache
+    let layout = Layout::from_size_align(SIZE, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null());
+    assert!(CACHE.lock().is_none());
+    unsafe { dealloc(ptr, layout) };
+}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -0,0 +1,130 @@
+// tests/huge_pages.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use capeos::memory::{
+    self,
+    region::RegionKind,
+    walk::{MappedPageSize, PageTableWalker},
+    KernelMemory,
+};
+use core::panic::PanicInfo;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+const SIZE_2MIB: u64 = 2 * 1024 * 1024;
+
+fn page_size(m: &mut KernelMemory, addr: VirtAddr) -> Option<MappedPageSize> {
+    PageTableWalker::new(&mut m.mapper).translate(addr).map(|t| t.page_size)
+}
+
+#[test_case]
+fn large_region_uses_huge_pages() {
+    memory::with_kernel_memory(|m| {
+        let region = m.map_region(2 * SIZE_2MIB, RegionKind::Buffer, FLAGS).unwrap();
+        assert!(region.start.is_aligned(SIZE_2MIB));
+        assert_eq!(page_size(m, region.start), Some(MappedPageSize::Size2MiB));
+        assert_eq!(page_size(m, region.start + SIZE_2MIB + 42u64), Some(MappedPageSize::Size2MiB));
+
+        let translation = PageTableWalker::new(&mut m.mapper).translate(region.start).unwrap();
+        assert!(translation.flags.contains(FLAGS | PageTableFlags::HUGE_PAGE));
+
+        let last: *mut u64 = (region.end() - 8u64).as_mut_ptr();
+        unsafe {
+            last.write_volatile(0xdead_beef);
+            assert_eq!(last.read_volatile(), 0xdead_beef);
+        }
+        m.unmap_region(region.start).unwrap();
+        assert_eq!(page_size(m, region.start), None);
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn small_region_uses_small_pages() {
+    memory::with_kernel_memory(|m| {
+        let region = m.map_region(SIZE_2MIB - 4096, RegionKind::Buffer, FLAGS).unwrap();
+        assert_eq!(page_size(m, region.start), Some(MappedPageSize::Size4KiB));
+        m.unmap_region(region.start).unwrap();
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn unaligned_range_mixes_page_sizes() {
+    memory::with_kernel_memory(|m| {
+        // reserve an aligned region, but map it starting one page in
+        let region = m.regions.allocate(3 * SIZE_2MIB, RegionKind::Buffer, FLAGS).unwrap();
+        let start = region.start + 4096u64;
+        m.map_range(start, None, 3 * SIZE_2MIB - 4096, FLAGS).unwrap();
+
+        assert_eq!(page_size(m, start), Some(MappedPageSize::Size4KiB));
+        assert_eq!(page_size(m, region.start + SIZE_2MIB), Some(MappedPageSize::Size2MiB));
+        assert_eq!(page_size(m, region.start + 2 * SIZE_2MIB), Some(MappedPageSize::Size2MiB));
+        assert_eq!(page_size(m, region.start), None);
+
+        m.unmap_region(region.start).unwrap();
+        assert_eq!(page_size(m, region.start + SIZE_2MIB), None);
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn huge_pages_give_frames_back() {
+    memory::with_kernel_memory(|m| {
+        // map once so that the page tables exist
+        let region = m.map_region(2 * SIZE_2MIB, RegionKind::Buffer, FLAGS).unwrap();
+        m.unmap_region(region.start).unwrap();
+
+        let free = m.frame_allocator.free_frames();
+        let region = m.map_region(2 * SIZE_2MIB, RegionKind::Buffer, FLAGS).unwrap();
+        assert_eq!(m.frame_allocator.free_frames(), free - 2 * 512);
+        m.unmap_region(region.start).unwrap();
+        assert_eq!(m.frame_allocator.free_frames(), free);
+    })
+    .unwrap();
+}
+
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn heap_grows_with_huge_pages() {
+    use alloc::vec::Vec;
+
+    // needs to grow the heap by more than 2 MiB at once
+    let vec: Vec<u8> = Vec::with_capacity(3 * SIZE_2MIB as usize);
+    let start = VirtAddr::from_ptr(vec.as_ptr());
+    let aligned = start.align_up(SIZE_2MIB);
+    let size = memory::with_kernel_memory(|m| page_size(m, aligned)).unwrap();
+    assert_eq!(size, Some(MappedPageSize::Size2MiB));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
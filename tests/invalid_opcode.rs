@@ -0,0 +1,39 @@
+// tests/invalid_opcode.rs
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use capeos::{exit_qemu, interrupts, serial_print, serial_println, QemuExitCode};
+
+// vector of an invalid opcode exception (#UD)
+const VECTOR: u8 = 6;
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    serial_print!("invalid_opcode::invalid_opcode... \t");
+
+    capeos::init();
+    // ud2 is guaranteed to be an invalid opcode
+    unsafe { asm!("ud2", options(nomem, nostack)) };
+
+    panic!("Execution continued after invalid opcode");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // the exception must have been reported under its own name, not as a double fault
+    if interrupts::last_exception() == Some(VECTOR)
+        && interrupts::exception_name(VECTOR) == Some("INVALID OPCODE")
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        serial_println!("reported exception: {:?}\n", interrupts::last_exception());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    capeos::hlt_loop();
+}
@@ -0,0 +1,72 @@
+// tests/irq_handlers.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use capeos::interrupts::{self, RegisterIrqError, IRQ_LINES, TIMER_IRQ};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    capeos::init();
+    test_main();
+    capeos::hlt_loop();
+}
+
+static FIRST: AtomicU64 = AtomicU64::new(0);
+static SECOND: AtomicU64 = AtomicU64::new(0);
+
+fn first_handler() {
+    FIRST.fetch_add(1, Ordering::Relaxed);
+}
+
+fn second_handler() {
+    SECOND.fetch_add(1, Ordering::Relaxed);
+}
+
+fn unused_handler() {}
+
+// wait until 'counter' was incremented 'ticks' times
+fn wait_for(counter: &AtomicU64, ticks: u64) {
+    let start = counter.load(Ordering::Relaxed);
+    while counter.load(Ordering::Relaxed) < start + ticks {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn handlers_share_the_timer_line() {
+    interrupts::register_irq(TIMER_IRQ, first_handler).unwrap();
+    interrupts::register_irq(TIMER_IRQ, second_handler).unwrap();
+
+    // more than one tick means the end of interrupt was sent
+    wait_for(&FIRST, 3);
+    wait_for(&SECOND, 3);
+}
+
+#[test_case]
+fn invalid_line_is_rejected() {
+    let result = interrupts::register_irq(IRQ_LINES, unused_handler);
+    assert_eq!(result, Err(RegisterIrqError::InvalidLine));
+}
+
+#[test_case]
+fn lines_have_a_handler_limit() {
+    // no device raises line 5 in QEMU
+    let line = 5;
+    let result = loop {
+        if let Err(err) = interrupts::register_irq(line, unused_handler) {
+            break err;
+        }
+    };
+    assert_eq!(result, RegisterIrqError::TooManyHandlers);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
@@ -0,0 +1,146 @@
+// tests/memory_regions.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use capeos::memory::{
+    self,
+    region::{RegionKind, KERNEL_VM_START},
+};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{PageTableFlags, Translate},
+    PhysAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
+    use x86_64::VirtAddr;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+#[test_case]
+fn heap_is_a_region() {
+    use capeos::allocator::HEAP_START;
+    use x86_64::VirtAddr;
+
+    let region = memory::with_kernel_memory(|m| m.regions.find(VirtAddr::new(HEAP_START as u64)))
+        .unwrap()
+        .expect("heap region missing");
+    assert_eq!(region.kind, RegionKind::Heap);
+}
+
+#[test_case]
+fn mapped_region_is_usable() {
+    let region = memory::with_kernel_memory(|m| m.map_region(3 * 4096, RegionKind::Buffer, FLAGS))
+        .unwrap()
+        .expect("mapping failed");
+    assert!(region.start.as_u64() >= KERNEL_VM_START);
+    assert_eq!(region.size, 3 * 4096);
+
+    let words: *mut u64 = region.start.as_mut_ptr();
+    let count = region.size as usize / 8;
+    unsafe {
+        for i in 0..count {
+            words.add(i).write_volatile(i as u64);
+        }
+        for i in 0..count {
+            assert_eq!(words.add(i).read_volatile(), i as u64);
+        }
+    }
+    memory::with_kernel_memory(|m| m.unmap_region(region.start)).unwrap().unwrap();
+}
+
+#[test_case]
+fn regions_do_not_overlap() {
+    memory::with_kernel_memory(|m| {
+        let a = m.map_region(4096, RegionKind::Buffer, FLAGS).unwrap();
+        let b = m.map_region(5000, RegionKind::Stack("test"), FLAGS).unwrap();
+        assert_eq!(b.size, 2 * 4096);
+        assert!(a.end() <= b.start || b.end() <= a.start);
+        assert_eq!(m.regions.find(b.start + 4100u64).map(|r| r.kind), Some(RegionKind::Stack("test")));
+        m.unmap_region(a.start).unwrap();
+        m.unmap_region(b.start).unwrap();
+        assert!(m.regions.find(a.start).is_none());
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn oversized_regions_are_rejected() {
+    use capeos::memory::region::{RegionAllocator, RegionError, KERNEL_VM_SIZE};
+
+    let mut regions = RegionAllocator::new();
+    for size in [u64::MAX, u64::MAX - 4095, KERNEL_VM_SIZE + 1] {
+        let result = regions.allocate(size, RegionKind::Buffer, FLAGS);
+        assert!(matches!(result, Err(RegionError::OutOfAddressSpace)));
+    }
+    assert!(regions.allocate(KERNEL_VM_SIZE, RegionKind::Buffer, FLAGS).is_ok());
+}
+
+#[test_case]
+fn unmap_gives_frames_back() {
+    memory::with_kernel_memory(|m| {
+        let free = m.frame_allocator.free_frames();
+        let region = m.map_region(16 * 4096, RegionKind::Buffer, FLAGS).unwrap();
+        assert!(m.frame_allocator.free_frames() <= free - 16);
+        m.unmap_region(region.start).unwrap();
+        assert!(m.mapper.translate_addr(region.start).is_none());
+        // page tables created for the region stay allocated
+        let again = m.map_region(16 * 4096, RegionKind::Buffer, FLAGS).unwrap();
+        assert_eq!(again.start, region.start);
+        let used = free - m.frame_allocator.free_frames();
+        m.unmap_region(again.start).unwrap();
+        assert_eq!(free - m.frame_allocator.free_frames(), used - 16);
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn mmio_maps_device_memory() {
+    // the vga buffer is identity mapped by the bootloader
+    let vga = 0xb8000 as *mut u16;
+    let region = memory::with_kernel_memory(|m| m.map_mmio(PhysAddr::new(0xb8000), 4000, FLAGS))
+        .unwrap()
+        .expect("mmio mapping failed");
+    assert_eq!(region.kind, RegionKind::Mmio);
+
+    let mmio: *mut u16 = region.start.as_mut_ptr();
+    unsafe {
+        mmio.add(80 * 24).write_volatile(0x0f41);
+        assert_eq!(vga.add(80 * 24).read_volatile(), 0x0f41);
+    }
+
+    // the frames belong to the device and must not be freed
+    memory::with_kernel_memory(|m| {
+        let free = m.frame_allocator.free_frames();
+        m.unmap_region(region.start).unwrap();
+        assert_eq!(m.frame_allocator.free_frames(), free);
+    })
+    .unwrap();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
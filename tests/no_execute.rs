@@ -0,0 +1,85 @@
+// tests/no_execute.rs
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use capeos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+entry_point!(main);
+
+// address of the heap code, checked by the page fault handler
+static CODE_ADDR: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("no_execute::execute_from_heap... \t");
+
+    capeos::gdt::init();
+    TEST_IDT.load();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    execute_from_heap();
+    serial_println!("[test did not page fault]");
+    exit_qemu(QemuExitCode::Failed);
+    capeos::hlt_loop();
+}
+
+fn execute_from_heap() {
+    // a single `ret` instruction
+    let code = Box::new([0xc3u8; 16]);
+    CODE_ADDR.store(code.as_ptr() as u64, Ordering::SeqCst);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let addr = Cr2::read().as_u64();
+    if addr == CODE_ADDR.load(Ordering::SeqCst)
+        && error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault at {:#x}, {:?}\n", addr, error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    capeos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
@@ -0,0 +1,110 @@
+// tests/page_fault_handlers.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use capeos::memory::{
+    self,
+    region::{Region, RegionKind},
+    KernelMemory,
+};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    structures::{idt::PageFaultErrorCode, paging::PageTableFlags},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+// last fault seen by the handler
+static FAULTS: AtomicU64 = AtomicU64::new(0);
+static FAULT_ADDR: AtomicU64 = AtomicU64::new(0);
+static FAULT_WRITE: AtomicU64 = AtomicU64::new(0);
+
+// maps the faulting page, like a demand paging handler would
+fn map_on_fault(
+    memory: &mut KernelMemory,
+    region: &Region,
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> bool {
+    FAULTS.fetch_add(1, Ordering::SeqCst);
+    FAULT_ADDR.store(addr.as_u64(), Ordering::SeqCst);
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    FAULT_WRITE.store(write as u64, Ordering::SeqCst);
+
+    let page = addr.align_down(4096u64);
+    memory.map_range(page, None, 4096, region.flags).is_ok()
+}
+
+fn reserve(pages: u64) -> Region {
+    memory::with_kernel_memory(|m| {
+        let region = m.regions.allocate(pages * 4096, RegionKind::Buffer, FLAGS).unwrap();
+        m.regions.set_fault_handler(region.start, map_on_fault).unwrap();
+        region
+    })
+    .unwrap()
+}
+
+#[test_case]
+fn fault_calls_region_handler() {
+    let region = reserve(4);
+    let faults = FAULTS.load(Ordering::SeqCst);
+
+    let addr = region.start + 2 * 4096u64 + 8u64;
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe { ptr.write_volatile(42) };
+
+    assert_eq!(FAULTS.load(Ordering::SeqCst), faults + 1);
+    assert_eq!(FAULT_ADDR.load(Ordering::SeqCst), addr.as_u64());
+    assert_eq!(FAULT_WRITE.load(Ordering::SeqCst), 1);
+    assert_eq!(unsafe { ptr.read_volatile() }, 42);
+
+    memory::with_kernel_memory(|m| m.unmap_region(region.start)).unwrap().unwrap();
+}
+
+#[test_case]
+fn resolved_fault_is_not_repeated() {
+    let region = reserve(1);
+    let faults = FAULTS.load(Ordering::SeqCst);
+
+    let ptr: *const u64 = region.start.as_ptr();
+    let first = unsafe { ptr.read_volatile() };
+    let second = unsafe { ptr.read_volatile() };
+    assert_eq!(first, second);
+    assert_eq!(FAULTS.load(Ordering::SeqCst), faults + 1);
+    assert_eq!(FAULT_WRITE.load(Ordering::SeqCst), 0);
+
+    memory::with_kernel_memory(|m| m.unmap_region(region.start)).unwrap().unwrap();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
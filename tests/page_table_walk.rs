@@ -0,0 +1,138 @@
+// tests/page_table_walk.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use capeos::memory::{
+    self,
+    walk::{MappedPageSize, PageTableWalker, Translation},
+};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{PageTableFlags, Translate},
+    PhysAddr,
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+fn translate(addr: u64) -> Option<Translation> {
+    memory::with_kernel_memory(|m| PageTableWalker::new(&mut m.mapper).translate(VirtAddr::new(addr)))
+        .unwrap()
+}
+
+#[test_case]
+fn vga_buffer_is_identity_mapped() {
+    let translation = translate(0xb8000 + 42).expect("vga buffer not mapped");
+    assert_eq!(translation.phys_addr, PhysAddr::new(0xb8000 + 42));
+    assert_eq!(translation.page_start, VirtAddr::new(0xb8000));
+    assert_eq!(translation.page_size, MappedPageSize::Size4KiB);
+    assert!(translation.flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn physical_memory_offset_maps_vga_buffer() {
+    let offset = memory::with_kernel_memory(|m| m.mapper.phys_offset()).unwrap();
+    let translation = translate(offset.as_u64() + 0xb8000).expect("physical memory not mapped");
+    assert_eq!(translation.phys_addr, PhysAddr::new(0xb8000));
+}
+
+#[test_case]
+fn heap_is_writable() {
+    use capeos::allocator::HEAP_START;
+
+    let translation = translate(HEAP_START as u64).expect("heap not mapped");
+    assert!(translation.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!translation.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
+
+#[test_case]
+fn code_is_not_writable() {
+    let translation = translate(capeos::hlt_loop as fn() -> ! as usize as u64).expect("code not mapped");
+    assert!(!translation.flags.contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn code_is_executable() {
+    let translation = translate(capeos::hlt_loop as fn() -> ! as usize as u64).unwrap();
+    assert!(!translation.flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn rodata_is_read_only() {
+    static TABLE: [u64; 4] = [1, 2, 3, 4];
+
+    let translation = translate(TABLE.as_ptr() as u64).expect("rodata not mapped");
+    assert!(!translation.flags.contains(PageTableFlags::WRITABLE));
+    assert!(translation.flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn data_is_not_executable() {
+    use core::sync::atomic::AtomicU64;
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let translation = translate(COUNTER.as_ptr() as u64).expect("data not mapped");
+    assert!(translation.flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn heap_and_stack_are_not_executable() {
+    use capeos::allocator::HEAP_START;
+
+    let stack_value = 0u64;
+    let heap = translate(HEAP_START as u64).unwrap();
+    let stack = translate(&stack_value as *const u64 as u64).unwrap();
+    assert!(heap.flags.contains(PageTableFlags::NO_EXECUTE));
+    assert!(stack.flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn unmapped_address_is_none() {
+    // below the kernel, never mapped by the bootloader
+    assert!(translate(0x1000).is_none());
+    assert!(translate(0x_7777_7777_0000).is_none());
+}
+
+#[test_case]
+fn matches_mapper_translation() {
+    let stack_value = 0u64;
+    let addr = &stack_value as *const u64 as u64;
+    memory::with_kernel_memory(|m| {
+        let expected = m.mapper.translate_addr(VirtAddr::new(addr));
+        let translation = PageTableWalker::new(&mut m.mapper).translate(VirtAddr::new(addr));
+        assert_eq!(translation.map(|t| t.phys_addr), expected);
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn dump_does_not_panic() {
+    memory::with_kernel_memory(|m| PageTableWalker::new(&mut m.mapper).dump()).unwrap();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
@@ -0,0 +1,97 @@
+// tests/realloc_benchmark.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+// runs 'workload' and reports how its reallocations were served
+//
+// returns the number of in-place and moved reallocations
+#[cfg(all(
+    any(feature = "alloc-fixed-block", feature = "alloc-linked-list"),
+    not(feature = "alloc-debug")
+))]
+fn bench(name: &str, workload: impl FnOnce()) -> (usize, usize) {
+    use capeos::{allocator::heap_stats, serial_print};
+    use core::arch::x86_64::_rdtsc;
+
+    let before = heap_stats();
+    let start = unsafe { _rdtsc() };
+    workload();
+    let cycles = unsafe { _rdtsc() } - start;
+    let after = heap_stats();
+
+    let in_place = after.in_place_reallocs - before.in_place_reallocs;
+    let moved = after.moved_reallocs - before.moved_reallocs;
+    serial_print!("{}: {} in place, {} moved, {} cycles  ", name, in_place, moved, cycles);
+    (in_place, moved)
+}
+
+#[cfg(all(
+    any(feature = "alloc-fixed-block", feature = "alloc-linked-list"),
+    not(feature = "alloc-debug")
+))]
+#[test_case]
+fn push_with_exact_reserve() {
+    use alloc::vec::Vec;
+
+    // every push reallocates, but only a few of them need a copy
+    let (in_place, moved) = bench("exact reserve", || {
+        let mut vec = Vec::new();
+        for i in 0..4096 {
+            vec.reserve_exact(1);
+            vec.push(i as u8);
+        }
+    });
+    assert!(moved * 10 < in_place);
+}
+
+#[cfg(all(
+    any(feature = "alloc-fixed-block", feature = "alloc-linked-list"),
+    not(feature = "alloc-debug")
+))]
+#[test_case]
+fn push_doubling() {
+    use alloc::vec::Vec;
+
+    // large vectors grow into the free space behind them
+    let (in_place, _) = bench("doubling", || {
+        let mut vec = Vec::new();
+        for i in 0..4 * 1024u64 {
+            vec.push(i);
+        }
+    });
+    assert!(in_place > 0);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
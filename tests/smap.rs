@@ -0,0 +1,82 @@
+// tests/smap.rs
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use capeos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+// a page in the lower half that is not used by the kernel
+const USER_PAGE: u64 = 0x_7000_0000_0000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::memory::{self, buddy::BuddyFrameAllocator};
+
+    serial_print!("smap::kernel_access_to_user_page... \t");
+
+    capeos::gdt::init();
+    TEST_IDT.load();
+    if !capeos::cpu::init_protection().smap {
+        serial_println!("[failed]\n");
+        serial_println!("Error: SMAP not supported, run QEMU with -cpu qemu64,+smap\n");
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    memory::with_kernel_memory(|m| m.map_range(VirtAddr::new(USER_PAGE), None, 4096, flags))
+        .unwrap()
+        .expect("failed to map user page");
+
+    // without stac, SMAP turns this into a page fault
+    let value = unsafe { (USER_PAGE as *const u64).read_volatile() };
+    serial_println!("[test did not page fault, read {:#x}]", value);
+    exit_qemu(QemuExitCode::Failed);
+    capeos::hlt_loop();
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let addr = Cr2::read().as_u64();
+    if addr == USER_PAGE
+        && error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && !error_code.contains(PageFaultErrorCode::USER_MODE)
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault at {:#x}, {:?}\n", addr, error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    capeos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}
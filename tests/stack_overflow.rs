@@ -4,16 +4,30 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use capeos::{serial_print};
 
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
     serial_print!("stack_overflow::stack_overflow... \t");
 
     capeos::gdt::init();
     init_test_idt();
-    
+
+    // run the double fault handler on a stack with a guard page
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    capeos::gdt::init_stacks();
+
     // trigger a cpu stack overflow
     stack_overflow();
 
@@ -57,7 +71,19 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
+    use x86_64::registers::control::Cr2;
+
+    // the overflow must have hit the guard page of the boot stack
+    match capeos::memory::stack_guard_hit(Cr2::read()) {
+        Some("boot") => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        other => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: fault at {:?} reported as {:?}\n", Cr2::read(), other);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
     loop {}
 }
\ No newline at end of file
@@ -0,0 +1,85 @@
+// tests/user_access.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(capeos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use capeos::cpu::{self, Protection, UserAccessError};
+use capeos::memory;
+use core::panic::PanicInfo;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+// a page in the lower half that is not used by the kernel
+const USER_PAGE: u64 = 0x_7000_0000_0000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use capeos::allocator;
+    use capeos::memory::buddy::BuddyFrameAllocator;
+
+    capeos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    memory::with_kernel_memory(|m| m.map_range(VirtAddr::new(USER_PAGE), None, 4096, flags))
+        .unwrap()
+        .expect("failed to map user page");
+
+    test_main();
+    capeos::hlt_loop();
+}
+
+#[test_case]
+fn protection_is_enabled() {
+    // QEMU is started with -cpu qemu64,+smep,+smap,+umip
+    let supported = Protection::supported();
+    assert!(supported.smep && supported.smap);
+    assert_eq!(Protection::enabled(), supported);
+}
+
+#[test_case]
+fn copy_to_and_from_user() {
+    let data = *b"hello user space";
+    let mut read = [0u8; 16];
+    unsafe {
+        cpu::copy_to_user(USER_PAGE as *mut u8, &data).unwrap();
+        cpu::copy_from_user(&mut read, USER_PAGE as *const u8).unwrap();
+    }
+    assert_eq!(read, data);
+}
+
+#[test_case]
+fn kernel_addresses_are_rejected() {
+    use capeos::allocator::HEAP_START;
+
+    let mut buf = [0u8; 8];
+    let result = unsafe { cpu::copy_from_user(&mut buf, HEAP_START as *const u8) };
+    assert_eq!(result, Err(UserAccessError::NotUserAddress));
+
+    // kernel regions lie in the lower half as well, just below user space
+    let result = unsafe { cpu::copy_from_user(&mut buf, (cpu::USER_SPACE_START - 4) as *const u8) };
+    assert_eq!(result, Err(UserAccessError::NotUserAddress));
+
+    // a range that starts in user space but runs past its end
+    let end = (cpu::USER_SPACE_END - 4) as *mut u8;
+    let result = unsafe { cpu::copy_to_user(end, &buf) };
+    assert_eq!(result, Err(UserAccessError::NotUserAddress));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    capeos::test_panic_handler(info)
+}